@@ -1,14 +1,30 @@
-use ::http::HeaderMap;
+use ::http::header::{HeaderName, HeaderValue};
+use ::http::{HeaderMap, Uri};
 use anyhow::{Error, Result};
 use colored::*;
 use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::http;
+use crate::proto::template::DataFile;
+use crate::proto::uri::split_path_template;
+use crate::proto::{
+    AddrStrategy, Backoff, Http2Options, Proxy, ReconnectPolicy, ReplayOrder, RequestSpec, Resolve,
+    TcpOptions, TlsOptions,
+};
 use crate::results::WorkerResult;
 use crate::runtime;
+use crate::script::Script;
 use crate::utils::div_mod;
 
+/// The bounds HTTP/2 places on the frame and window sizes.
+const MIN_H2_FRAME_SIZE: u32 = 16_384;
+const MAX_H2_FRAME_SIZE: u32 = 16_777_215;
+const MAX_H2_WINDOW_SIZE: u32 = 2_147_483_647;
+
 /// The customisable settings that build the benchmark's behaviour.
 #[derive(Clone, Debug)]
 pub struct BenchmarkSettings {
@@ -19,18 +35,66 @@ pub struct BenchmarkSettings {
     /// framework.
     pub connections: usize,
 
-    /// The host connection / url.
-    pub host: String,
+    /// The urls to benchmark paired with their relative weights.
+    pub hosts: Vec<(String, u32)>,
+
+    /// The requests replayed from a file, relative paths are resolved
+    /// against the first host.
+    pub requests: Vec<RequestSpec>,
+
+    /// The order the requests are sent in.
+    pub replay_order: ReplayOrder,
 
     /// The bench mark type e.g. http1 only.
     pub bench_type: http::BenchType,
 
+    /// The number of concurrent HTTP/2 or HTTP/3 streams per connection.
+    pub streams: usize,
+
+    /// The number of HTTP/1 requests pipelined on each connection.
+    pub pipeline: usize,
+
+    /// The flow control, frame size and keep-alive tuning of HTTP/2
+    /// connections.
+    pub http2: Http2Options,
+
+    /// How TLS connections verify the server and authenticate the client.
+    pub tls: TlsOptions,
+
+    /// Connects over this Unix domain socket instead of TCP.
+    pub unix_socket: Option<PathBuf>,
+
+    /// How connections pick between the addresses a host resolves to.
+    pub addr_strategy: AddrStrategy,
+
+    /// How often hosts are looked up again, they are only looked up once
+    /// at startup when unset.
+    pub dns_refresh: Option<Duration>,
+
+    /// Fixed addresses connected to instead of looking the hosts up.
+    pub resolve: Vec<Resolve>,
+
+    /// The local addresses connections are made from, taking turns.
+    pub bind: Vec<IpAddr>,
+
+    /// The socket options of TCP connections.
+    pub tcp: TcpOptions,
+
+    /// The proxy connections are tunnelled through, if any.
+    pub proxy: Option<Proxy>,
+
+    /// How connections are made again once they fail or close.
+    pub reconnect: ReconnectPolicy,
+
     /// The duration of the benchmark.
     pub duration: Duration,
 
     /// Display the percentile table.
     pub display_percentile: bool,
 
+    /// Display the requests, errors and reconnects of each connection.
+    pub display_per_connection: bool,
+
     /// Display the result data as a json.
     pub display_json: bool,
 
@@ -38,9 +102,376 @@ pub struct BenchmarkSettings {
     pub rounds: usize,
 
     pub headers: HeaderMap,
+
+    /// The CSV data file used by request templates.
+    pub data: Option<Arc<DataFile>>,
+
+    /// The script hooking into the requests and results.
+    pub script: Option<Arc<Script>>,
+}
+
+impl Default for BenchmarkSettings {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            connections: 1,
+            hosts: Vec::new(),
+            requests: Vec::new(),
+            replay_order: ReplayOrder::Random,
+            bench_type: http::BenchType::HTTP1,
+            streams: 1,
+            pipeline: 1,
+            http2: Http2Options::default(),
+            tls: TlsOptions::default(),
+            unix_socket: None,
+            addr_strategy: AddrStrategy::First,
+            dns_refresh: None,
+            resolve: Vec::new(),
+            bind: Vec::new(),
+            tcp: TcpOptions::default(),
+            proxy: None,
+            reconnect: ReconnectPolicy::default(),
+            duration: Duration::from_secs(10),
+            display_percentile: false,
+            display_per_connection: false,
+            display_json: false,
+            rounds: 1,
+            headers: HeaderMap::new(),
+            data: None,
+            script: None,
+        }
+    }
+}
+
+impl BenchmarkSettings {
+    /// Creates a builder starting from the default settings.
+    pub fn builder() -> BenchmarkSettingsBuilder {
+        BenchmarkSettingsBuilder::default()
+    }
+}
+
+/// Builds a set of `BenchmarkSettings`, checking the settings are valid
+/// before they are used.
+///
+/// ```no_run
+/// # async fn bench() -> anyhow::Result<()> {
+/// use std::time::Duration;
+///
+/// let settings = rewrk::BenchmarkSettings::builder()
+///     .host("http://127.0.0.1:5050")
+///     .connections(64)
+///     .duration(Duration::from_secs(5))
+///     .build()?;
+///
+/// let result = rewrk::run(settings).await?;
+/// println!("{} req/sec", result.avg_request_per_sec());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct BenchmarkSettingsBuilder {
+    settings: BenchmarkSettings,
+}
+
+impl BenchmarkSettingsBuilder {
+    /// The number of worker threads used by `start_benchmark`.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.settings.threads = threads;
+        self
+    }
+
+    /// The number of concurrent connections, shared between the threads.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.settings.connections = connections;
+        self
+    }
+
+    /// Adds a url to benchmark with a weight of 1.
+    pub fn host(self, url: impl Into<String>) -> Self {
+        self.weighted_host(url, 1)
+    }
+
+    /// Adds a url to benchmark with the given relative weight.
+    ///
+    /// Urls on the same host share connections and each request picks one
+    /// by weight. Urls on different hosts can't share a connection, so
+    /// instead each host gets a share of the connections by its total
+    /// weight and a slower host completes fewer requests than its weight.
+    pub fn weighted_host(mut self, url: impl Into<String>, weight: u32) -> Self {
+        self.settings.hosts.push((url.into(), weight));
+        self
+    }
+
+    /// Adds a request to replay, relative paths are resolved against the
+    /// first host.
+    pub fn request(mut self, request: RequestSpec) -> Self {
+        self.settings.requests.push(request);
+        self
+    }
+
+    /// The order the requests are sent in.
+    pub fn replay_order(mut self, order: ReplayOrder) -> Self {
+        self.settings.replay_order = order;
+        self
+    }
+
+    /// The protocol used to connect to the hosts.
+    pub fn bench_type(mut self, bench_type: http::BenchType) -> Self {
+        self.settings.bench_type = bench_type;
+        self
+    }
+
+    /// The number of requests kept in flight on each HTTP/2 or HTTP/3
+    /// connection.
+    ///
+    /// With more than one, a request's latency starts once a stream is
+    /// free to send it on rather than when it's queued on the connection.
+    pub fn streams(mut self, streams: usize) -> Self {
+        self.settings.streams = streams;
+        self
+    }
+
+    /// The number of HTTP/1 requests written at once on each connection,
+    /// anything above 1 enables pipelining.
+    pub fn pipeline(mut self, pipeline: usize) -> Self {
+        self.settings.pipeline = pipeline;
+        self
+    }
+
+    /// Tunes the HTTP/2 connections, ignored by the other protocols.
+    pub fn http2(mut self, http2: Http2Options) -> Self {
+        self.settings.http2 = http2;
+        self
+    }
+
+    /// Sets how TLS connections verify the server and authenticate the
+    /// client.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.settings.tls = tls;
+        self
+    }
+
+    /// Connects over a Unix domain socket instead of TCP, the hosts are
+    /// still sent in the `Host` header.
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Sets how connections pick between the addresses a host resolves
+    /// to.
+    pub fn addr_strategy(mut self, strategy: AddrStrategy) -> Self {
+        self.settings.addr_strategy = strategy;
+        self
+    }
+
+    /// Looks the hosts up again once this long has passed, so connections
+    /// made later on follow DNS changes.
+    pub fn dns_refresh(mut self, interval: Duration) -> Self {
+        self.settings.dns_refresh = Some(interval);
+        self
+    }
+
+    /// Connects to the given addresses instead of looking the host up,
+    /// the url still sets the `Host` header and TLS server name.
+    pub fn resolve(mut self, resolve: Resolve) -> Self {
+        self.settings.resolve.push(resolve);
+        self
+    }
+
+    /// Adds a local address to connect from, connections take turns
+    /// between the addresses to spread them over more ephemeral ports.
+    pub fn bind(mut self, addr: IpAddr) -> Self {
+        self.settings.bind.push(addr);
+        self
+    }
+
+    /// Sets the socket options of TCP connections, ignored by unix sockets
+    /// and HTTP/3.
+    pub fn tcp(mut self, tcp: TcpOptions) -> Self {
+        self.settings.tcp = tcp;
+        self
+    }
+
+    /// Tunnels connections through a HTTP (`CONNECT`) or SOCKS5 proxy.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.settings.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets how long connections wait between attempts to connect and
+    /// how many attempts they make before giving up.
+    pub fn reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.settings.reconnect = reconnect;
+        self
+    }
+
+    /// How long each round of the benchmark runs for.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.settings.duration = duration;
+        self
+    }
+
+    /// Displays the percentile table of the latencies.
+    pub fn display_percentile(mut self, display: bool) -> Self {
+        self.settings.display_percentile = display;
+        self
+    }
+
+    /// Displays the stats of each connection.
+    pub fn display_per_connection(mut self, display: bool) -> Self {
+        self.settings.display_per_connection = display;
+        self
+    }
+
+    /// Displays the results as json instead of text.
+    pub fn display_json(mut self, display: bool) -> Self {
+        self.settings.display_json = display;
+        self
+    }
+
+    /// The number of times the benchmark is repeated.
+    pub fn rounds(mut self, rounds: usize) -> Self {
+        self.settings.rounds = rounds;
+        self
+    }
+
+    /// Adds a header sent with every request.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.settings.headers.append(name, value);
+        self
+    }
+
+    /// The CSV data file filling in the request templates.
+    pub fn data(mut self, data: DataFile) -> Self {
+        self.settings.data = Some(Arc::new(data));
+        self
+    }
+
+    /// The script hooking into the requests and results.
+    pub fn script(mut self, script: Script) -> Self {
+        self.settings.script = Some(Arc::new(script));
+        self
+    }
+
+    pub fn build(self) -> Result<BenchmarkSettings> {
+        let settings = self.settings;
+
+        if settings.hosts.is_empty() && settings.requests.is_empty() {
+            return Err(Error::msg("no hosts or requests to benchmark given"));
+        }
+
+        let weights = settings.hosts.iter().map(|(_, weight)| *weight);
+        if weights
+            .chain(settings.requests.iter().map(|r| r.weight))
+            .any(|weight| weight == 0)
+        {
+            return Err(Error::msg("request weights must be at least 1"));
+        }
+
+        let urls = settings.hosts.iter().map(|(url, _)| url.as_str());
+        for url in urls.chain(settings.requests.iter().map(|r| r.url.as_str())) {
+            split_path_template(url).map_err(|e| Error::msg(e.to_string()))?;
+        }
+
+        if settings.connections == 0 {
+            return Err(Error::msg("at least one connection is required"));
+        }
+
+        if settings.streams == 0 {
+            return Err(Error::msg("at least one stream is required"));
+        }
+
+        if settings.streams > 1 && matches!(settings.bench_type, http::BenchType::HTTP1) {
+            return Err(Error::msg(
+                "multiple streams per connection require HTTP/2 or HTTP/3",
+            ));
+        }
+
+        if settings.pipeline == 0 {
+            return Err(Error::msg("a pipeline depth of at least one is required"));
+        }
+
+        if settings.pipeline > 1 && !matches!(settings.bench_type, http::BenchType::HTTP1) {
+            return Err(Error::msg("pipelining is only supported with HTTP/1"));
+        }
+
+        if let Some(size) = settings.http2.max_frame_size {
+            if !(MIN_H2_FRAME_SIZE..=MAX_H2_FRAME_SIZE).contains(&size) {
+                return Err(Error::msg(format!(
+                    "the HTTP/2 max frame size must be between {} and {}",
+                    MIN_H2_FRAME_SIZE, MAX_H2_FRAME_SIZE,
+                )));
+            }
+        }
+
+        let windows = [
+            settings.http2.initial_stream_window,
+            settings.http2.initial_connection_window,
+        ];
+        if windows
+            .iter()
+            .flatten()
+            .any(|&size| size > MAX_H2_WINDOW_SIZE)
+        {
+            return Err(Error::msg(format!(
+                "HTTP/2 window sizes must be at most {}",
+                MAX_H2_WINDOW_SIZE,
+            )));
+        }
+
+        if settings.tls.cert.is_some() != settings.tls.key.is_some() {
+            return Err(Error::msg(
+                "a client certificate needs both a cert and a key",
+            ));
+        }
+
+        if settings.unix_socket.is_some() && matches!(settings.bench_type, http::BenchType::HTTP3) {
+            return Err(Error::msg("HTTP/3 can't connect over a unix socket"));
+        }
+
+        if settings.unix_socket.is_some() && !settings.bind.is_empty() {
+            return Err(Error::msg("can't bind a local address to a unix socket"));
+        }
+
+        if settings.proxy.is_some() {
+            if settings.unix_socket.is_some() {
+                return Err(Error::msg("can't use a proxy with a unix socket"));
+            }
+
+            if matches!(settings.bench_type, http::BenchType::HTTP3) {
+                return Err(Error::msg("HTTP/3 can't be tunnelled through a proxy"));
+            }
+
+            // Only the proxy is looked up, the target is resolved when the
+            // tunnel is opened so an override for it would go unused.
+            if !settings.resolve.is_empty() {
+                return Err(Error::msg("can't use a proxy with resolve overrides"));
+            }
+        }
+
+        if settings.reconnect.max_attempts == Some(0) {
+            return Err(Error::msg("at least one connect attempt is required"));
+        }
+
+        if let Backoff::Exponential { initial, max } = settings.reconnect.backoff {
+            if initial > max {
+                return Err(Error::msg(
+                    "the initial reconnect delay can't be longer than the max delay",
+                ));
+            }
+        }
+
+        if settings.threads == 0 {
+            return Err(Error::msg("at least one thread is required"));
+        }
+
+        Ok(settings)
+    }
 }
 
-/// Builds the runtime with the given settings and blocks on the main future.
+/// Builds the runtime with the given settings and blocks on the main future,
+/// displaying the results of each round.
 pub fn start_benchmark(settings: BenchmarkSettings) {
     let rt = runtime::get_rt(settings.threads);
     let rounds = settings.rounds;
@@ -48,9 +479,14 @@ pub fn start_benchmark(settings: BenchmarkSettings) {
     for i in 0..rounds {
         if !is_json {
             println!("Beginning round {}...", i + 1);
+            display_header(&settings);
         }
 
-        if let Err(e) = rt.block_on(run(settings.clone())) {
+        let result = rt
+            .block_on(run(settings.clone()))
+            .and_then(|result| display_result(&settings, result));
+
+        if let Err(e) = result {
             eprintln!("failed to run benchmark round due to error: {:?}", e);
             return;
         }
@@ -72,34 +508,18 @@ pub fn start_benchmark(settings: BenchmarkSettings) {
 /// extracted from the handle.
 ///
 /// The results are then merged into a single set of averages across workers.
-async fn run(settings: BenchmarkSettings) -> Result<()> {
+pub async fn run(settings: BenchmarkSettings) -> Result<WorkerResult> {
     let predict_size = settings.duration.as_secs() * 10_000;
 
-    let handles = http::start_tasks(
-        settings.duration,
-        settings.connections,
-        settings.host.clone(),
-        settings.bench_type,
-        predict_size as usize,
-        settings.headers,
-    )
-    .await;
+    let requests = resolve_requests(&settings)?;
+
+    let handles = http::start_tasks(&settings, &requests, predict_size as usize).await;
 
     let handles = match handles {
         Ok(v) => v,
         Err(e) => return Err(Error::msg(format!("error parsing uri: {}", e))),
     };
 
-    if !settings.display_json {
-        println!(
-            "Benchmarking {} connections with {} threads @ {} for {}",
-            string(settings.connections).cyan(),
-            string(settings.threads).cyan(),
-            settings.host.cyan(),
-            humanize(settings.duration),
-        );
-    }
-
     let mut combiner = WorkerResult::default();
     for handle in handles {
         let result = match handle.await {
@@ -114,28 +534,172 @@ async fn run(settings: BenchmarkSettings) -> Result<()> {
         }
     }
 
-    if settings.display_json {
-        combiner.display_json();
-        return Ok(());
+    Ok(combiner)
+}
+
+/// Builds the list of requests to send, resolving any replayed requests
+/// against the first host.
+fn resolve_requests(settings: &BenchmarkSettings) -> Result<Vec<RequestSpec>> {
+    if settings.requests.is_empty() {
+        let requests = settings
+            .hosts
+            .iter()
+            .map(|(url, weight)| RequestSpec::get(url.clone(), *weight))
+            .collect();
+
+        return Ok(requests);
     }
 
-    // prevent div-by-zero panics
-    if combiner.total_requests() == 0 {
-        println!("No requests completed successfully");
-        return Ok(());
+    let base = settings.hosts.first().map(|(url, _)| url.as_str());
+
+    settings
+        .requests
+        .iter()
+        .map(|request| request.clone().resolve(base))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::msg(format!("error resolving requests: {}", e)))
+}
+
+fn display_header(settings: &BenchmarkSettings) {
+    let hosts: Vec<String> = if settings.requests.is_empty() {
+        settings.hosts.iter().map(|(url, _)| url.clone()).collect()
+    } else {
+        resolve_requests(settings)
+            .map(|requests| replayed_hosts(&requests))
+            .unwrap_or_default()
+    };
+
+    println!(
+        "Benchmarking {} connections with {} threads @ {} for {}",
+        string(settings.connections).cyan(),
+        string(settings.threads).cyan(),
+        hosts.join(", ").cyan(),
+        humanize(settings.duration),
+    );
+
+    if settings.pipeline > 1 {
+        println!(
+            "Pipelining {} requests per connection, results are not comparable with \
+             non-pipelined runs",
+            string(settings.pipeline).yellow(),
+        );
     }
 
-    combiner.display_latencies();
-    combiner.display_requests();
-    combiner.display_transfer();
+    if let Some(path) = settings.unix_socket.as_ref() {
+        println!(
+            "Connecting over unix socket {}",
+            path.display().to_string().cyan()
+        );
+    }
 
-    if settings.display_percentile {
-        combiner.display_percentile_table();
+    if let Some(proxy) = settings.proxy.as_ref() {
+        println!(
+            "Tunnelling through proxy {}",
+            format!("{}:{}", proxy.host, proxy.port).cyan()
+        );
+    }
+
+    if !settings.requests.is_empty() {
+        println!(
+            "Replaying {} requests in {:?} order",
+            string(settings.requests.len()).cyan(),
+            settings.replay_order,
+        );
+    }
+}
+
+fn display_result(settings: &BenchmarkSettings, mut result: WorkerResult) -> Result<()> {
+    if settings.display_json {
+        let mut out = result.json();
+        if settings.pipeline > 1 {
+            out["pipeline"] = settings.pipeline.into();
+        }
+
+        if settings.display_per_connection {
+            out["per_connection"] = result.per_connection_json();
+        }
+
+        println!("{}", out);
+    } else if result.total_requests() == 0 {
+        // The connection sections are still shown as they explain why
+        // nothing completed, the rest would divide by zero.
+        result.display_tls_handshakes();
+        result.display_dns_lookups();
+        result.display_reconnects();
+
+        if settings.display_per_connection {
+            result.display_per_connection();
+        }
+
+        println!("No requests completed successfully");
+    } else {
+        if settings.pipeline > 1 {
+            println!(
+                "  {}",
+                format!("Pipelined: {} requests per batch", settings.pipeline).yellow()
+            );
+        }
+
+        result.display_latencies();
+        result.display_requests();
+        result.display_transfer();
+        result.display_targets();
+        result.display_addresses();
+
+        if settings.streams > 1 {
+            result.display_streams(settings.streams);
+        }
+
+        if matches!(settings.bench_type, http::BenchType::Auto) {
+            result.display_protocols();
+        }
+
+        result.display_tls_handshakes();
+        result.display_dns_lookups();
+        result.display_reconnects();
+
+        if settings.display_per_connection {
+            result.display_per_connection();
+        }
+
+        if settings.display_percentile {
+            result.display_percentile_table();
+        }
+    }
+
+    if let Some(script) = settings.script.as_ref() {
+        match script.done(&result) {
+            Ok(Some(summary)) => println!("{}", summary),
+            Ok(None) => {}
+            Err(e) => return Err(Error::msg(format!("error running script done(): {}", e))),
+        }
     }
 
     Ok(())
 }
 
+/// Collects the distinct `scheme://authority` pairs of the replayed
+/// requests.
+fn replayed_hosts(requests: &[RequestSpec]) -> Vec<String> {
+    let mut hosts: Vec<String> = Vec::new();
+
+    for request in requests {
+        let uri = match request.url.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => continue,
+        };
+
+        if let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority()) {
+            let host = format!("{}://{}", scheme, authority);
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+    }
+
+    hosts
+}
+
 /// Uber lazy way of just stringing everything and limiting it to 2 d.p
 fn string<T: Display>(value: T) -> String {
     format!("{:.2}", value)
@@ -173,3 +737,177 @@ fn humanize(time: Duration) -> String {
 
     human.join(", ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(builder: BenchmarkSettingsBuilder) -> String {
+        builder.build().err().unwrap().to_string()
+    }
+
+    #[test]
+    fn checks_the_hosts_and_connections() {
+        let host = || BenchmarkSettings::builder().host("http://example.com");
+
+        assert_eq!(
+            rejected(BenchmarkSettings::builder()),
+            "no hosts or requests to benchmark given"
+        );
+        assert_eq!(
+            rejected(BenchmarkSettings::builder().weighted_host("http://example.com", 0)),
+            "request weights must be at least 1"
+        );
+        assert_eq!(
+            rejected(BenchmarkSettings::builder().host("http://{{seq}}.example.com/")),
+            "templates are only supported in the path and query of a url: \
+             http://{{seq}}.example.com/"
+        );
+        assert_eq!(
+            rejected(host().connections(0)),
+            "at least one connection is required"
+        );
+        assert_eq!(
+            rejected(host().threads(0)),
+            "at least one thread is required"
+        );
+        assert!(host().build().is_ok());
+    }
+
+    #[test]
+    fn checks_streams_per_connection() {
+        let streams = |streams, bench_type| {
+            BenchmarkSettings::builder()
+                .host("http://example.com")
+                .streams(streams)
+                .bench_type(bench_type)
+        };
+
+        assert_eq!(
+            rejected(streams(0, http::BenchType::HTTP2)),
+            "at least one stream is required"
+        );
+        assert_eq!(
+            rejected(streams(4, http::BenchType::HTTP1)),
+            "multiple streams per connection require HTTP/2 or HTTP/3"
+        );
+        assert!(streams(4, http::BenchType::HTTP2).build().is_ok());
+    }
+
+    #[test]
+    fn checks_the_pipeline_depth() {
+        let pipeline = |depth, bench_type| {
+            BenchmarkSettings::builder()
+                .host("http://example.com")
+                .pipeline(depth)
+                .bench_type(bench_type)
+        };
+
+        assert_eq!(
+            rejected(pipeline(0, http::BenchType::HTTP1)),
+            "a pipeline depth of at least one is required"
+        );
+        assert_eq!(
+            rejected(pipeline(8, http::BenchType::HTTP2)),
+            "pipelining is only supported with HTTP/1"
+        );
+        assert!(pipeline(8, http::BenchType::HTTP1).build().is_ok());
+    }
+
+    #[test]
+    fn checks_http2_sizes() {
+        let http2 = |http2| {
+            BenchmarkSettings::builder()
+                .host("http://example.com")
+                .http2(http2)
+        };
+
+        for size in [MIN_H2_FRAME_SIZE - 1, MAX_H2_FRAME_SIZE + 1] {
+            let builder = http2(Http2Options {
+                max_frame_size: Some(size),
+                ..Default::default()
+            });
+            assert_eq!(
+                rejected(builder),
+                "the HTTP/2 max frame size must be between 16384 and 16777215"
+            );
+        }
+
+        let builder = http2(Http2Options {
+            initial_connection_window: Some(MAX_H2_WINDOW_SIZE + 1),
+            ..Default::default()
+        });
+        assert_eq!(
+            rejected(builder),
+            "HTTP/2 window sizes must be at most 2147483647"
+        );
+
+        let builder = http2(Http2Options {
+            initial_stream_window: Some(MAX_H2_WINDOW_SIZE),
+            initial_connection_window: Some(MAX_H2_WINDOW_SIZE),
+            max_frame_size: Some(MAX_H2_FRAME_SIZE),
+            ..Default::default()
+        });
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn rejects_a_client_cert_without_a_key() {
+        let tls = |cert: Option<&str>, key: Option<&str>| {
+            BenchmarkSettings::builder()
+                .host("https://example.com")
+                .tls(TlsOptions {
+                    cert: cert.map(Into::into),
+                    key: key.map(Into::into),
+                    ..Default::default()
+                })
+        };
+
+        let msg = "a client certificate needs both a cert and a key";
+        assert_eq!(rejected(tls(Some("client.pem"), None)), msg);
+        assert_eq!(rejected(tls(None, Some("client.key"))), msg);
+        assert!(tls(Some("client.pem"), Some("client.key")).build().is_ok());
+    }
+
+    #[test]
+    fn rejects_http3_over_unix_sockets() {
+        let builder = BenchmarkSettings::builder()
+            .host("https://localhost")
+            .unix_socket("/tmp/rewrk.sock")
+            .bench_type(http::BenchType::HTTP3);
+
+        assert_eq!(rejected(builder), "HTTP/3 can't connect over a unix socket");
+    }
+
+    #[test]
+    fn rejects_resolving_through_a_proxy() {
+        let builder = BenchmarkSettings::builder()
+            .host("http://example.com")
+            .resolve("example.com:80:127.0.0.1".parse().unwrap())
+            .proxy("http://127.0.0.1:3128".parse().unwrap());
+
+        assert_eq!(
+            rejected(builder),
+            "can't use a proxy with resolve overrides"
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_proxy_routes() {
+        let proxied = || {
+            BenchmarkSettings::builder()
+                .host("https://localhost")
+                .proxy("socks5://127.0.0.1:1080".parse().unwrap())
+        };
+
+        assert_eq!(
+            rejected(proxied().unix_socket("/tmp/rewrk.sock")),
+            "can't use a proxy with a unix socket"
+        );
+        assert_eq!(
+            rejected(proxied().bench_type(http::BenchType::HTTP3)),
+            "HTTP/3 can't be tunnelled through a proxy"
+        );
+        assert!(proxied().build().is_ok());
+    }
+}
@@ -1,9 +1,9 @@
-use http::HeaderMap;
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
 
+use crate::bench::BenchmarkSettings;
 use crate::error::AnyError;
 use crate::proto;
+use crate::proto::RequestSpec;
 use crate::results::WorkerResult;
 
 pub type Handle = JoinHandle<Result<WorkerResult, AnyError>>;
@@ -16,26 +16,99 @@ pub enum BenchType {
 
     /// Sets the http protocol to be used as h2
     HTTP2,
+
+    /// Sets the http protocol to be used as h2, upgrading cleartext
+    /// connections from HTTP/1.1 rather than using prior knowledge
+    HTTP2Upgrade,
+
+    /// Negotiates either h1 or h2 with ALPN, cleartext connections use h1
+    Auto,
+
+    /// Sets the http protocol to be used as h3 over QUIC
+    HTTP3,
 }
 
 pub async fn start_tasks(
-    time_for: Duration,
-    connections: usize,
-    uri_string: String,
-    bench_type: BenchType,
+    settings: &BenchmarkSettings,
+    requests: &[RequestSpec],
     predicted_size: usize,
-    headers: HeaderMap,
 ) -> Result<Vec<Handle>, AnyError> {
-    let client =
-        proto::parse::get_client(time_for, uri_string, headers, bench_type, predicted_size).await?;
+    let clients = proto::parse::get_clients(settings, requests, predicted_size).await?;
+    let connections = settings.connections;
+
+    let weights: Vec<u64> = clients.iter().map(|(_, weight)| *weight).collect();
+    let shares = share_connections(&weights, connections);
 
     let mut handles: Vec<Handle> = Vec::with_capacity(connections);
 
-    for _ in 0..connections {
-        let handle: Handle = tokio::spawn(client.clone().start_instance());
+    for ((client, _), share) in clients.iter().zip(shares) {
+        for _ in 0..share {
+            let connection_id = handles.len();
+            let handle: Handle = tokio::spawn(client.clone().start_instance(connection_id));
 
-        handles.push(handle);
+            handles.push(handle);
+        }
     }
 
     Ok(handles)
 }
+
+/// Splits the connections between the clients proportionally to their
+/// weights, giving every client at least one connection where possible.
+///
+/// This only approximates the weights for the requests, each connection
+/// sends requests as fast as its server answers them.
+fn share_connections(weights: &[u64], connections: usize) -> Vec<usize> {
+    let total: u64 = weights.iter().sum();
+    let spare = connections.saturating_sub(weights.len()) as u64;
+
+    let mut shares: Vec<usize> = weights
+        .iter()
+        .map(|w| {
+            let guaranteed = (connections >= weights.len()) as u64;
+            (guaranteed + spare * w / total.max(1)) as usize
+        })
+        .collect();
+
+    // Hand out any connections lost to rounding, heaviest weights first.
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|a, b| weights[*b].cmp(&weights[*a]));
+
+    let assigned: usize = shares.iter().sum();
+    for i in order.into_iter().cycle().take(connections - assigned) {
+        shares[i] += 1;
+    }
+
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::share_connections;
+
+    #[test]
+    fn shares_connections_by_weight() {
+        assert_eq!(share_connections(&[1, 1], 4), vec![2, 2]);
+        assert_eq!(share_connections(&[3, 1], 10), vec![7, 3]);
+    }
+
+    #[test]
+    fn gives_every_client_a_connection() {
+        assert_eq!(share_connections(&[100, 1], 2), vec![1, 1]);
+        assert_eq!(share_connections(&[1], 3), vec![3]);
+    }
+
+    #[test]
+    fn hands_out_leftovers_heaviest_first() {
+        assert_eq!(share_connections(&[1, 2, 1], 4), vec![1, 2, 1]);
+        assert_eq!(share_connections(&[1, 5, 2], 2), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn always_hands_out_every_connection() {
+        for connections in 0..20 {
+            let shares = share_connections(&[7, 3, 1, 0], connections);
+            assert_eq!(shares.iter().sum::<usize>(), connections);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! ReWrk
+//!
+//! The benchmarking engine behind the `rewrk` CLI, usable from other
+//! tools such as integration test harnesses.
+//!
+//! Settings are built with `BenchmarkSettings::builder()` and ran with
+//! `run`, which returns the combined `WorkerResult` of every connection
+//! rather than displaying it.
+
+pub mod bench;
+pub mod http;
+pub mod proto;
+pub mod results;
+pub mod script;
+
+pub(crate) mod error;
+mod runtime;
+pub(crate) mod utils;
+
+pub use crate::bench::{run, start_benchmark, BenchmarkSettings, BenchmarkSettingsBuilder};
+pub use crate::error::AnyError;
+pub use crate::http::BenchType;
+pub use crate::proto::{
+    AddrStrategy, Backoff, Client, Connect, Http2Options, HttpProtocol, Proxy, ReconnectPolicy,
+    ReplayOrder, RequestSpec, Resolve, TcpOptions, TlsOptions, TlsVersion,
+};
+pub use crate::results::{ConnectionStats, WorkerResult};
+pub use crate::utils::BoxedFuture;
@@ -1,34 +1,122 @@
 use crate::error::AnyError;
-use crate::proto::tcp_stream::CustomTcpStream;
-use crate::proto::uri::ParsedUri;
+use crate::proto::connector::{AsyncStream, CloseReason, RawConnection, Route, Sender, TcpOptions};
+use crate::proto::pipeline::{self, ResponseReader};
+use crate::proto::proxy::{Proxy, Tunnel};
+use crate::proto::tcp_stream::ByteCounter;
+use crate::proto::template::{DataFile, RenderContext};
+use crate::proto::tls::TlsHandshake;
+use crate::proto::uri::{Address, ParsedUri};
 use crate::proto::{Connect, Connection, HttpProtocol};
-use crate::results::WorkerResult;
+use crate::results::{ConnectionStats, TargetResult, WorkerResult};
+use crate::script::{Script, ScriptInstance, ScriptedRequest};
 use crate::utils::BoxedFuture;
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use http::HeaderMap;
-use tokio::net::TcpStream;
-use tokio::time::sleep;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, sleep_until, timeout_at};
 
-use hyper::client::conn;
-use hyper::{Body, StatusCode};
+use http::HeaderMap;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, StatusCode};
 
-use tower::{Service, ServiceExt};
+use futures_util::future::try_join_all;
 
 pub trait Client {
-    fn start_instance(self: Arc<Self>) -> BoxedFuture<'static, Result<WorkerResult, AnyError>>;
+    fn start_instance(
+        self: Arc<Self>,
+        connection_id: usize,
+    ) -> BoxedFuture<'static, Result<WorkerResult, AnyError>>;
+}
+
+/// The settings shared by every connection of a client.
+#[derive(Clone)]
+pub struct ClientOptions {
+    pub time_for: Duration,
+    pub predicted_size: usize,
+    pub data: Option<Arc<DataFile>>,
+    pub script: Option<Arc<Script>>,
+
+    /// The number of requests kept in flight on each connection, when
+    /// more than 1 the latency leaves out waiting for a free stream.
+    pub streams: usize,
+
+    /// The number of HTTP/1 requests written at once, pipelining is
+    /// disabled when this is 1.
+    pub pipeline: usize,
+
+    /// The local addresses connections are made from, taking turns.
+    pub bind: Vec<IpAddr>,
+
+    /// The socket options of TCP connections.
+    pub tcp: TcpOptions,
+
+    /// The proxy connections are tunnelled through, if any.
+    pub proxy: Option<Proxy>,
+
+    /// How connections are made again once they fail or close.
+    pub reconnect: ReconnectPolicy,
+}
+
+/// How long to wait between attempts to connect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    /// The same delay before every attempt.
+    Fixed(Duration),
+
+    /// A delay starting at `initial` and doubling after each failed
+    /// attempt, up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+/// How a connection is made again after failing or being closed by the
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub backoff: Backoff,
+
+    /// The number of failed attempts in a row before the connection gives
+    /// up, it keeps trying until the benchmark is over when unset.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            backoff: Backoff::Fixed(Duration::from_millis(200)),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay after the given number of failed attempts in a row.
+    fn delay(&self, failed: usize) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 1u32.checked_shl(failed.saturating_sub(1) as u32);
+                initial.saturating_mul(factor.unwrap_or(u32::MAX)).min(max)
+            }
+        }
+    }
 }
 
 pub struct BenchmarkClient<C, P> {
     connector: C,
     protocol: P,
-    time_for: Duration,
-    predicted_size: usize,
     parsed_uri: ParsedUri,
-    headers: HeaderMap,
+    options: ClientOptions,
+    seq: AtomicU64,
+    bind_cursor: AtomicUsize,
+    tunnel: Option<Arc<Tunnel>>,
 }
 
 impl<C, P> Client for BenchmarkClient<C, P>
@@ -36,8 +124,157 @@ where
     C: Connect + Send + Sync + 'static,
     P: HttpProtocol + Copy + Send + Sync + 'static,
 {
-    fn start_instance(self: Arc<Self>) -> BoxedFuture<'static, Result<WorkerResult, AnyError>> {
-        Box::pin(self.start_ins())
+    fn start_instance(
+        self: Arc<Self>,
+        connection_id: usize,
+    ) -> BoxedFuture<'static, Result<WorkerResult, AnyError>> {
+        if self.options.pipeline > 1 {
+            Box::pin(self.start_pipelined(connection_id))
+        } else {
+            Box::pin(self.start_ins(connection_id))
+        }
+    }
+}
+
+/// The results of a single stream of requests sent one after the other
+/// over a connection.
+struct Stream<'a> {
+    times: Vec<Duration>,
+    targets: Vec<TargetResult>,
+
+    /// The results since the current connection was made, moved to the
+    /// connection's address once it closes.
+    address: TargetResult,
+    success: usize,
+    error: usize,
+
+    /// The decoded size of the response bodies read and request bodies
+    /// written.
+    body_read: usize,
+    body_written: usize,
+    script: Option<SharedScript<'a>>,
+}
+
+/// A connection's script instance, shared by all of its streams so the
+/// script keeps a single state per connection.
+type SharedScript<'a> = Arc<std::sync::Mutex<ScriptInstance<'a>>>;
+
+impl Stream<'_> {
+    /// Records the outcome of a request to the target at the given index,
+    /// `None` meaning the request failed.
+    fn record(&mut self, idx: usize, took: Option<Duration>) {
+        match took {
+            Some(took) => {
+                self.success += 1;
+                self.times.push(took);
+                self.targets[idx].record(took);
+                self.address.record(took);
+            }
+            None => {
+                self.error += 1;
+                self.targets[idx].error += 1;
+                self.address.error += 1;
+            }
+        }
+    }
+}
+
+/// Keeps track of the connections made over the benchmark, a new one is
+/// made whenever the server closes the previous one.
+#[derive(Default)]
+struct Connections {
+    protocols: BTreeMap<&'static str, usize>,
+    handshakes: Vec<TlsHandshake>,
+
+    /// The address of the current connection.
+    address: String,
+    addresses: BTreeMap<String, TargetResult>,
+
+    /// The DNS lookups made by this connection when refreshing the
+    /// addresses, shared with the connect attempts.
+    dns: std::sync::Mutex<DnsLookups>,
+
+    /// The number of connections made and the time spent making them,
+    /// including failed attempts.
+    connects: usize,
+    connecting: Duration,
+
+    /// The number of failed attempts to connect.
+    connect_failures: usize,
+
+    /// Why each connection closed before the benchmark was over.
+    close_reasons: BTreeMap<&'static str, usize>,
+
+    /// If the connection ran out of attempts to connect.
+    gave_up: bool,
+}
+
+/// The DNS lookups made when refreshing the addresses, along with the
+/// initial lookup.
+#[derive(Default)]
+struct DnsLookups {
+    times: Vec<Duration>,
+    failures: usize,
+}
+
+impl Connections {
+    /// Records a new connection, the results of the streams on the
+    /// previous connection must be flushed first.
+    fn record(&mut self, addr: &Address, protocol: &'static str, tls: Option<TlsHandshake>) {
+        *self.protocols.entry(protocol).or_default() += 1;
+        self.handshakes.extend(tls);
+        self.connects += 1;
+        self.address = addr.to_string();
+    }
+
+    /// Records why the current connection closed.
+    fn closed(&mut self, reason: CloseReason) {
+        *self.close_reasons.entry(reason.as_str()).or_default() += 1;
+    }
+
+    /// Moves the results of the streams since the current connection was
+    /// made to its address.
+    fn flush(&mut self, streams: &mut [Stream<'_>]) {
+        let result = self.addresses.entry(self.address.clone()).or_default();
+        for stream in streams.iter_mut() {
+            *result = std::mem::take(result).combine(std::mem::take(&mut stream.address));
+        }
+    }
+
+    /// Counts the connections using each negotiated TLS version and
+    /// cipher suite.
+    fn sessions(&self) -> BTreeMap<String, usize> {
+        let mut sessions = BTreeMap::new();
+        for handshake in self.handshakes.iter() {
+            *sessions.entry(handshake.session()).or_default() += 1;
+        }
+
+        sessions
+    }
+}
+
+/// Tracks the number of requests in flight on a connection.
+#[derive(Default)]
+struct InFlight {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl InFlight {
+    fn enter(&self) -> InFlightGuard<'_> {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+        InFlightGuard(self)
+    }
+}
+
+/// Marks a request as no longer in flight once dropped, which also covers
+/// requests cancelled when the connection closes.
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
@@ -46,95 +283,463 @@ where
     C: Connect + Send + Sync + 'static,
     P: HttpProtocol + Copy + Send + Sync + 'static,
 {
-    pub fn new(
-        connector: C,
-        protocol: P,
-        time_for: Duration,
-        predicted_size: usize,
-        parsed_uri: ParsedUri,
-        headers: HeaderMap,
-    ) -> Self {
+    pub fn new(connector: C, protocol: P, parsed_uri: ParsedUri, options: ClientOptions) -> Self {
+        let tunnel = options
+            .proxy
+            .clone()
+            .map(|proxy| Arc::new(Tunnel::new(proxy, &parsed_uri.host, parsed_uri.port)));
+
         Self {
             connector,
             protocol,
-            time_for,
-            predicted_size,
             parsed_uri,
-            headers,
+            options,
+            seq: AtomicU64::new(0),
+            bind_cursor: AtomicUsize::new(0),
+            tunnel,
         }
     }
 
-    pub async fn start_ins(self: Arc<Self>) -> Result<WorkerResult, AnyError> {
+    pub async fn start_ins(
+        self: Arc<Self>,
+        connection_id: usize,
+    ) -> Result<WorkerResult, AnyError> {
         let start = Instant::now();
-        let counter = Arc::new(AtomicUsize::new(0));
+        let time_for = self.options.time_for;
+        let counter = Arc::new(ByteCounter::default());
+
+        let mut connections = Connections::default();
 
-        let mut connection = match self.connect_retry(start, self.time_for, &counter).await {
+        let ts = Instant::now();
+        let connect = self.connect_retry(
+            start,
+            &counter,
+            &connections.dns,
+            &mut connections.connect_failures,
+        );
+        let (connection, mut addr) = match connect.await {
             Ok(conn) => conn,
             Err(_) => {
-                return Ok(WorkerResult::default());
+                return Ok(self.never_connected(connection_id, start, ts.elapsed(), connections));
             }
         };
+        connections.connecting += ts.elapsed();
 
-        let mut times: Vec<Duration> = Vec::with_capacity(self.predicted_size);
-
-        let mut complete: usize = 0;
-        let mut error: usize = 0;
-        while self.time_for > start.elapsed() {
-            tokio::select! {
-                val = self.bench_request(&mut connection.send_request, &mut times) => {
-                    // if let Err(_e) = val {
-                        // Errors are ignored currently.
-                    // }
-                    if let Ok(is_complete) = val {
-                        if is_complete {
-                            complete += 1;
-                        } else {
-                            error += 1;
-                        }
-                    }
+        connections.record(&addr, connection.send_request.protocol(), connection.tls);
+
+        // The streams take turns dispatching requests on the connection,
+        // the lock is released before awaiting the response so multiple
+        // requests can be in flight on an HTTP/2 connection.
+        let mut send_request = Mutex::new(connection.send_request);
+        let mut handle = connection.handle;
+
+        let script = self.new_script(connection_id)?;
+        let mut streams = Vec::with_capacity(self.options.streams);
+        for _ in 0..self.options.streams {
+            streams.push(self.new_stream(script.clone()));
+        }
+
+        let in_flight = InFlight::default();
+        let deadline = start + time_for;
+
+        while time_for > start.elapsed() {
+            let reason = tokio::select! {
+                // Requests still unanswered at the deadline are left out,
+                // a stalled server shouldn't hold the worker past it.
+                _ = sleep_until(deadline.into()) => break,
+                ran = try_join_all(streams.iter_mut().map(|stream| {
+                    self.run_stream(&send_request, start, connection_id, stream, &in_flight)
+                })) => {
+                    ran?;
+                    continue;
                 },
-                _ = (&mut connection.handle) => {
-                    match self.connect_retry(start, self.time_for, &counter).await {
-                        Ok(conn) => connection = conn,
-                        // Errors are ignored currently.
-                        Err(_) => break,
+                reason = (&mut handle) => reason.unwrap_or(CloseReason::Error),
+                _ = self.address_gone(&addr, &connections.dns) => CloseReason::AddressChanged,
+            };
+
+            connections.closed(reason);
+
+            // Closes the connection when leaving it for a new address.
+            handle.abort();
+
+            let ts = Instant::now();
+            let connect = self
+                .connect_retry(
+                    start,
+                    &counter,
+                    &connections.dns,
+                    &mut connections.connect_failures,
+                )
+                .await;
+            connections.connecting += ts.elapsed();
+
+            match connect {
+                Ok((conn, new_addr)) => {
+                    connections.flush(&mut streams);
+                    connections.record(&new_addr, conn.send_request.protocol(), conn.tls);
+                    send_request = Mutex::new(conn.send_request);
+                    handle = conn.handle;
+                    addr = new_addr;
+                }
+                Err(_) => {
+                    connections.gave_up = time_for > start.elapsed();
+                    break;
+                }
+            }
+        }
+
+        // Drop the connection rather than letting hyper shut it down
+        // gracefully, so SO_LINGER 0 resets it instead of sending a FIN.
+        handle.abort();
+
+        let time_taken = start.elapsed();
+        let peak_in_flight = in_flight.peak.load(Ordering::Relaxed);
+        connections.flush(&mut streams);
+
+        Ok(self.collect_results(
+            connection_id,
+            time_taken,
+            streams,
+            &counter,
+            peak_in_flight,
+            connections,
+        ))
+    }
+
+    /// Benchmarks a HTTP/1 connection by writing a batch of requests at
+    /// once and then reading each of the responses back in order.
+    ///
+    /// The latency of each request is measured from when the batch was
+    /// written, the same way wrk measures pipelined requests.
+    pub async fn start_pipelined(
+        self: Arc<Self>,
+        connection_id: usize,
+    ) -> Result<WorkerResult, AnyError> {
+        let start = Instant::now();
+        let time_for = self.options.time_for;
+        let counter = Arc::new(ByteCounter::default());
+
+        let mut connections = Connections::default();
+
+        let ts = Instant::now();
+        let connect = self.connect_raw_retry(
+            start,
+            &counter,
+            &connections.dns,
+            &mut connections.connect_failures,
+        );
+        let (connection, mut addr) = match connect.await {
+            Ok(conn) => conn,
+            Err(_) => {
+                return Ok(self.never_connected(connection_id, start, ts.elapsed(), connections));
+            }
+        };
+        connections.connecting += ts.elapsed();
+
+        // Pipelined requests are always written as HTTP/1.1.
+        connections.record(&addr, "HTTP/1.1", connection.tls);
+        let mut io = connection.stream;
+
+        let deadline = start + time_for;
+        let refresh = self.parsed_uri.refresh_interval();
+        let mut refresh_at = refresh.map(|refresh| Instant::now() + refresh);
+        let mut stream = self.new_stream(self.new_script(connection_id)?);
+        let mut reader = ResponseReader::default();
+        let mut batch = Vec::with_capacity(self.options.pipeline);
+        let mut buf = Vec::new();
+
+        while time_for > start.elapsed() {
+            batch.clear();
+            buf.clear();
+
+            for _ in 0..self.options.pipeline {
+                let idx = self.parsed_uri.pick();
+
+                let scripted = scripted_request(&stream.script)?;
+                let encoded = match self.build_request(idx, connection_id, scripted) {
+                    Ok(req) => {
+                        let method = req.method().clone();
+                        pipeline::encode_request(req, &mut buf)
+                            .await
+                            .map(|body_len| (method, body_len))
+                    }
+                    Err(e) => Err(e),
+                };
+
+                match encoded {
+                    Ok((method, body_len)) => {
+                        stream.body_written += body_len;
+                        batch.push((idx, method));
                     }
+                    Err(_) => stream.record(idx, None),
                 }
-            };
+            }
+
+            if batch.is_empty() {
+                continue;
+            }
+
+            let mut closed = self
+                .send_batch(&mut io, &buf, &batch, &mut reader, &mut stream, deadline)
+                .await?;
+
+            // Batches are checked between rather than interrupted, the
+            // same as the addresses are on other connections otherwise.
+            if closed.is_none() && refresh_at.is_some_and(|at| at <= Instant::now()) {
+                refresh_at = refresh.map(|refresh| Instant::now() + refresh);
+                self.refresh_addrs(&connections.dns).await;
+
+                if !self.parsed_uri.has_addr(&addr) {
+                    closed = Some(CloseReason::AddressChanged);
+                }
+            }
+
+            if let Some(reason) = closed {
+                connections.closed(reason);
+                reader = ResponseReader::default();
+
+                let ts = Instant::now();
+                let connect = self
+                    .connect_raw_retry(
+                        start,
+                        &counter,
+                        &connections.dns,
+                        &mut connections.connect_failures,
+                    )
+                    .await;
+                connections.connecting += ts.elapsed();
+
+                match connect {
+                    Ok((conn, new_addr)) => {
+                        connections.flush(std::slice::from_mut(&mut stream));
+                        connections.record(&new_addr, "HTTP/1.1", conn.tls);
+                        io = conn.stream;
+                        addr = new_addr;
+                    }
+                    Err(_) => {
+                        connections.gave_up = time_for > start.elapsed();
+                        break;
+                    }
+                }
+            }
         }
 
         let time_taken = start.elapsed();
+        let pipeline = self.options.pipeline;
+        connections.flush(std::slice::from_mut(&mut stream));
 
-        let result = WorkerResult {
+        Ok(self.collect_results(
+            connection_id,
+            time_taken,
+            vec![stream],
+            &counter,
+            pipeline,
+            connections,
+        ))
+    }
+
+    /// Creates the connection's script instance, if there is a script.
+    fn new_script(&self, connection_id: usize) -> Result<Option<SharedScript<'_>>, AnyError> {
+        match self.options.script.as_ref() {
+            Some(script) => {
+                let instance = script.instance(connection_id)?;
+                Ok(Some(Arc::new(std::sync::Mutex::new(instance))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn new_stream<'a>(&'a self, script: Option<SharedScript<'a>>) -> Stream<'a> {
+        let predicted_size = self.options.predicted_size / self.options.streams;
+
+        Stream {
+            times: Vec::with_capacity(predicted_size),
+            targets: vec![TargetResult::default(); self.parsed_uri.targets.len()],
+            address: TargetResult::default(),
+            success: 0,
+            error: 0,
+            body_read: 0,
+            body_written: 0,
+            script,
+        }
+    }
+
+    /// Merges the results of each stream into the connection's result.
+    fn collect_results(
+        &self,
+        connection_id: usize,
+        time_taken: Duration,
+        streams: Vec<Stream>,
+        counter: &ByteCounter,
+        peak_in_flight: usize,
+        connections: Connections,
+    ) -> WorkerResult {
+        let mut times: Vec<Duration> = Vec::with_capacity(self.options.predicted_size);
+        let mut targets = vec![TargetResult::default(); self.parsed_uri.targets.len()];
+        let mut success = 0;
+        let mut error = 0;
+        let mut body_read = 0;
+        let mut body_written = 0;
+        for stream in streams {
+            times.extend(stream.times);
+            success += stream.success;
+            error += stream.error;
+            body_read += stream.body_read;
+            body_written += stream.body_written;
+
+            for (target, other) in targets.iter_mut().zip(stream.targets) {
+                *target = std::mem::take(target).combine(other);
+            }
+        }
+
+        let targets = self
+            .parsed_uri
+            .targets
+            .iter()
+            .map(|t| t.label().to_string())
+            .zip(targets)
+            .collect();
+
+        let dns = std::mem::take(&mut *connections.dns.lock().unwrap());
+
+        let stats = ConnectionStats {
+            id: connection_id,
+            requests: success,
+            errors: error,
+            reconnects: connections.connects.saturating_sub(1),
+            connect_time: connections.connecting,
+        };
+
+        WorkerResult {
             total_times: vec![time_taken],
             request_times: times,
-            buffer_sizes: vec![counter.load(Ordering::Acquire)],
-            success: complete,
-            error: error,
+            buffer_sizes: vec![counter.read.load(Ordering::Acquire)],
+            bytes_written: counter.written.load(Ordering::Acquire),
+            body_read,
+            body_written,
+            success,
+            error,
+            targets,
+            peak_in_flight: vec![peak_in_flight],
+            connections: vec![stats],
+            tls_sessions: connections.sessions(),
+            protocols: connections.protocols,
+            addresses: connections.addresses,
+            dns_lookup_times: dns.times,
+            dns_failures: dns.failures,
+            tls_handshake_times: connections.handshakes.iter().map(|h| h.took).collect(),
+            tls_resumed: connections.handshakes.iter().filter(|h| h.resumed).count(),
+            connect_failures: connections.connect_failures,
+            close_reasons: connections.close_reasons,
+            gave_up: connections.gave_up as usize,
+        }
+    }
+
+    /// The result of a connection which never managed to connect, either
+    /// running out of attempts or time.
+    fn never_connected(
+        &self,
+        connection_id: usize,
+        start: Instant,
+        connect_time: Duration,
+        connections: Connections,
+    ) -> WorkerResult {
+        WorkerResult {
+            connect_failures: connections.connect_failures,
+            gave_up: (self.options.time_for > start.elapsed()) as usize,
+            ..WorkerResult::never_connected(connection_id, connect_time)
+        }
+    }
+
+    /// Sends requests one after the other until the benchmark is over,
+    /// stopping early if the script fails.
+    async fn run_stream(
+        &self,
+        send_request: &Mutex<Sender>,
+        start: Instant,
+        connection_id: usize,
+        stream: &mut Stream<'_>,
+        in_flight: &InFlight,
+    ) -> Result<(), AnyError> {
+        while self.options.time_for > start.elapsed() {
+            let idx = self.parsed_uri.pick();
+
+            let took = self
+                .bench_request(send_request, idx, connection_id, stream, in_flight)
+                .await?;
+
+            stream.record(idx, took);
+        }
+
+        Ok(())
+    }
+
+    /// Builds the next request to the target at the given index, letting
+    /// the script modify it first.
+    fn build_request(
+        &self,
+        idx: usize,
+        connection_id: usize,
+        scripted: Option<ScriptedRequest>,
+    ) -> Result<Request<Body>, AnyError> {
+        let target = &self.parsed_uri.targets[idx];
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let ctx = RenderContext {
+            seq,
+            connection_id,
+            row: self.options.data.as_ref().map(|data| data.row(seq)),
         };
 
-        Ok(result)
+        match scripted {
+            Some(scripted) => {
+                let target = target.scripted(scripted)?;
+                self.protocol.get_request(&target, &ctx)
+            }
+            None => self.protocol.get_request(target, &ctx),
+        }
     }
 
-    // NOTE: Currently ignoring errors.
+    /// Sends a single request to the target at the given index, returning
+    /// the latency of the request if it completed successfully.
+    ///
+    /// Failed requests are counted rather than returned, only the script
+    /// failing is returned as an error.
     async fn bench_request(
         &self,
-        send_request: &mut conn::SendRequest<Body>,
-        times: &mut Vec<Duration>,
-    ) -> Result<bool, AnyError> {
-        let req = self
-            .protocol
-            .get_request(&self.parsed_uri.uri, &self.headers);
+        send_request: &Mutex<Sender>,
+        idx: usize,
+        connection_id: usize,
+        stream: &mut Stream<'_>,
+        in_flight: &InFlight,
+    ) -> Result<Option<Duration>, AnyError> {
+        let scripted = scripted_request(&stream.script)?;
+        let req = match self.build_request(idx, connection_id, scripted) {
+            Ok(req) => req,
+            Err(_) => return Ok(None),
+        };
+        let body_len = req.body().size_hint().exact().unwrap_or(0) as usize;
 
-        let ts = Instant::now();
+        // The latency includes waiting for the connection to be ready.
+        let mut ts = Instant::now();
+
+        let resp = match send_request.lock().await.dispatch(req).await {
+            Ok(resp) => resp,
+            Err(_) => return Ok(None),
+        };
+        stream.body_written += body_len;
 
-        if send_request.ready().await.is_err() {
-            return Ok(false);
+        // With several streams per connection that wait is for one of the
+        // other streams to free up rather than for the server, so the
+        // latency starts once the request is sent instead.
+        if self.options.streams > 1 {
+            ts = Instant::now();
         }
+        let _in_flight = in_flight.enter();
 
-        let resp = match send_request.call(req).await {
+        let resp = match resp.await {
             Ok(v) => v,
-            Err(_) => return Ok(false),
+            Err(_) => return Ok(None),
         };
 
         let took = ts.elapsed();
@@ -144,48 +749,442 @@ where
         // assert_eq!(status, StatusCode::OK);
         // println!("got status={:?}", status);
 
-        if status != StatusCode::OK {
-            return Ok(false);
-        }
-
-        let _buff = match hyper::body::to_bytes(resp).await {
+        // The body is read whatever the status, so the data transferred is
+        // counted for failed requests too and the connection can be reused.
+        let (parts, body) = resp.into_parts();
+        let _buff = match hyper::body::to_bytes(body).await {
             Ok(v) => v,
-            Err(_) => return Ok(false),
+            Err(_) => return Ok(None),
         };
+        stream.body_read += _buff.len();
 
         // println!("got body={:?}", _buff);
 
-        times.push(took);
+        if !is_success(&stream.script, status, &parts.headers, &_buff)? {
+            return Ok(None);
+        }
 
-        Ok(true)
+        Ok(Some(took))
+    }
+
+    /// Writes a batch of encoded requests and reads back their responses,
+    /// returning why the connection closed if it did. Any requests left
+    /// unanswered when the connection closes are counted as errors.
+    ///
+    /// Nothing is waited for past the deadline, requests still unanswered
+    /// by then are left out like the requests in flight on any other
+    /// connection when the benchmark ends.
+    async fn send_batch(
+        &self,
+        io: &mut Box<dyn AsyncStream>,
+        buf: &[u8],
+        batch: &[(usize, Method)],
+        reader: &mut ResponseReader,
+        stream: &mut Stream<'_>,
+        deadline: Instant,
+    ) -> Result<Option<CloseReason>, AnyError> {
+        let ts = Instant::now();
+
+        match timeout_at(deadline.into(), io.write_all(buf)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => {
+                for (idx, _) in batch {
+                    stream.record(*idx, None);
+                }
+                return Ok(Some(CloseReason::Error));
+            }
+            Err(_) => return Ok(None),
+        }
+
+        for (i, (idx, method)) in batch.iter().enumerate() {
+            let read = match timeout_at(deadline.into(), reader.read_response(io, method)).await {
+                Ok(read) => read,
+                Err(_) => return Ok(None),
+            };
+
+            let resp = match read {
+                Ok(resp) => resp,
+                Err(e) => {
+                    for (idx, _) in &batch[i..] {
+                        stream.record(*idx, None);
+                    }
+
+                    // Reaching the end of the stream means the server
+                    // closed the connection.
+                    return match e.downcast_ref::<io::Error>() {
+                        Some(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                            Ok(Some(CloseReason::ServerClosed))
+                        }
+                        _ => Ok(Some(CloseReason::Error)),
+                    };
+                }
+            };
+
+            let took = ts.elapsed();
+            stream.body_read += resp.body.len();
+            let success = is_success(&stream.script, resp.status, &resp.headers, &resp.body)?;
+            stream.record(*idx, success.then_some(took));
+
+            if resp.close {
+                for (idx, _) in &batch[i + 1..] {
+                    stream.record(*idx, None);
+                }
+                return Ok(Some(CloseReason::ServerClosed));
+            }
+        }
+
+        Ok(None)
     }
 
     async fn connect_retry(
         &self,
         start: Instant,
-        time_for: Duration,
-        counter: &Arc<AtomicUsize>,
-    ) -> Result<Connection, AnyError> {
-        while start.elapsed() < time_for {
-            let res = self.connect(counter).await;
+        counter: &Arc<ByteCounter>,
+        dns: &std::sync::Mutex<DnsLookups>,
+        failures: &mut usize,
+    ) -> Result<(Connection, Address), AnyError> {
+        let time_for = self.options.time_for;
+        let policy = &self.options.reconnect;
+        retry(start, time_for, policy, failures, || {
+            self.connect(counter, dns)
+        })
+        .await
+    }
+
+    /// Connects to the next address picked by the address strategy,
+    /// returning the connection along with the address.
+    async fn connect(
+        &self,
+        counter: &Arc<ByteCounter>,
+        dns: &std::sync::Mutex<DnsLookups>,
+    ) -> Result<(Connection, Address), AnyError> {
+        self.refresh_addrs(dns).await;
+        let addr = self.parsed_uri.pick_addr();
+        let route = self.route(&addr)?;
+
+        let conn = self
+            .connector
+            .connect(addr.clone(), route, counter.clone(), self.protocol)
+            .await?;
+
+        Ok((conn, addr))
+    }
+
+    async fn connect_raw_retry(
+        &self,
+        start: Instant,
+        counter: &Arc<ByteCounter>,
+        dns: &std::sync::Mutex<DnsLookups>,
+        failures: &mut usize,
+    ) -> Result<(RawConnection, Address), AnyError> {
+        let time_for = self.options.time_for;
+        let policy = &self.options.reconnect;
+        retry(start, time_for, policy, failures, || {
+            self.connect_raw(counter, dns)
+        })
+        .await
+    }
+
+    async fn connect_raw(
+        &self,
+        counter: &Arc<ByteCounter>,
+        dns: &std::sync::Mutex<DnsLookups>,
+    ) -> Result<(RawConnection, Address), AnyError> {
+        self.refresh_addrs(dns).await;
+        let addr = self.parsed_uri.pick_addr();
+        let route = self.route(&addr)?;
+
+        let conn = self
+            .connector
+            .connect_raw(addr.clone(), route, counter.clone())
+            .await?;
+
+        Ok((conn, addr))
+    }
+
+    /// Works out how the next connection to the address reaches the
+    /// server.
+    fn route(&self, addr: &Address) -> Result<Route, AnyError> {
+        Ok(Route {
+            local: self.pick_local(addr)?,
+            tcp: self.options.tcp,
+            tunnel: self.tunnel.clone(),
+        })
+    }
 
-            if let Ok(val) = res {
-                return Ok(val);
+    /// Picks the next local address to connect from, taking turns between
+    /// the ones of the same family as the address connected to.
+    fn pick_local(&self, addr: &Address) -> Result<Option<IpAddr>, AnyError> {
+        match addr {
+            Address::Tcp(addr) if !self.options.bind.is_empty() => {
+                let turn = self.bind_cursor.fetch_add(1, Ordering::Relaxed);
+                local_for(&self.options.bind, addr, turn).map(Some)
             }
+            _ => Ok(None),
+        }
+    }
 
-            sleep(Duration::from_millis(200)).await;
+    /// Looks the host up again when a refresh is due, recording how the
+    /// lookup went.
+    async fn refresh_addrs(&self, dns: &std::sync::Mutex<DnsLookups>) {
+        if let Some(took) = self.parsed_uri.take_initial_lookup() {
+            dns.lock().unwrap().times.push(took);
         }
 
-        Err("connection closed".into())
+        let outcome = match self.parsed_uri.refresh_addrs().await {
+            Some(outcome) => outcome,
+            None => return,
+        };
+
+        let mut dns = dns.lock().unwrap();
+        match outcome {
+            Ok(took) => dns.times.push(took),
+            Err(_) => dns.failures += 1,
+        }
     }
 
-    async fn connect(&self, counter: &Arc<AtomicUsize>) -> Result<Connection, AnyError> {
-        let stream = TcpStream::connect(&self.parsed_uri.addr).await?;
+    /// Refreshes the addresses on the refresh interval, resolving once
+    /// the host no longer has the address the connection was made to so
+    /// keep-alive connections follow DNS changes too. Never resolves when
+    /// the addresses aren't refreshed.
+    async fn address_gone(&self, addr: &Address, dns: &std::sync::Mutex<DnsLookups>) {
+        let refresh = match self.parsed_uri.refresh_interval() {
+            Some(refresh) => refresh,
+            None => return std::future::pending().await,
+        };
+
+        loop {
+            sleep(refresh).await;
+            self.refresh_addrs(dns).await;
 
-        let stream = CustomTcpStream::new(stream, counter.clone());
+            if !self.parsed_uri.has_addr(addr) {
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps trying to connect until it succeeds, the policy runs out of
+/// attempts or the benchmark is over, counting the failed attempts.
+async fn retry<T, F, Fut>(
+    start: Instant,
+    time_for: Duration,
+    policy: &ReconnectPolicy,
+    failures: &mut usize,
+    mut connect: F,
+) -> Result<T, AnyError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AnyError>>,
+{
+    let mut failed = 0;
+    while start.elapsed() < time_for {
+        let err = match connect().await {
+            Ok(val) => return Ok(val),
+            Err(e) => e,
+        };
+
+        failed += 1;
+        *failures += 1;
+
+        if policy.max_attempts.is_some_and(|max| failed >= max) {
+            return Err(format!("gave up connecting after {} attempts: {}", failed, err).into());
+        }
+
+        // The wait is cut short when the benchmark ends first.
+        let remaining = time_for.saturating_sub(start.elapsed());
+        sleep(policy.delay(failed).min(remaining)).await;
+    }
+
+    Err("connection closed".into())
+}
+
+/// Calls the script's `request` function if there is a script.
+///
+/// A failing script is returned as an error so the benchmark stops with
+/// the script's error, rather than counting every request as an error.
+fn scripted_request(
+    script: &Option<SharedScript<'_>>,
+) -> Result<Option<ScriptedRequest>, AnyError> {
+    match script.as_ref() {
+        Some(script) => script
+            .lock()
+            .unwrap()
+            .request()
+            .map_err(|e| format!("script request() failed: {}", e).into()),
+        None => Ok(None),
+    }
+}
+
+/// Decides if a response was successful, deferring to the script's
+/// `response` function when it has one.
+fn is_success(
+    script: &Option<SharedScript<'_>>,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<bool, AnyError> {
+    let mut script = script.as_ref().map(|script| script.lock().unwrap());
+
+    match script.as_mut() {
+        Some(script) if script.has_response() => {
+            let verdict = script
+                .response(status.as_u16(), headers, body)
+                .map_err(|e| format!("script response() failed: {}", e))?;
+
+            Ok(verdict.unwrap_or(status == StatusCode::OK))
+        }
+        _ => Ok(status == StatusCode::OK),
+    }
+}
 
-        let connection = self.connector.handshake(stream, self.protocol).await?;
+/// Picks the local address for the given turn from the ones of the same
+/// family as the address connected to.
+fn local_for(bind: &[IpAddr], addr: &SocketAddr, turn: usize) -> Result<IpAddr, AnyError> {
+    let candidates: Vec<IpAddr> = bind
+        .iter()
+        .filter(|local| local.is_ipv4() == addr.is_ipv4())
+        .copied()
+        .collect();
+
+    if candidates.is_empty() {
+        return Err(format!("no bind address of the same family as {}", addr).into());
+    }
+
+    Ok(candidates[turn % candidates.len()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exponential(initial: u64, max: u64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(initial),
+                max: Duration::from_millis(max),
+            },
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn tracks_the_peak_of_requests_in_flight() {
+        let in_flight = InFlight::default();
+
+        let first = in_flight.enter();
+        let second = in_flight.enter();
+        drop(first);
+        let third = in_flight.enter();
+        assert_eq!(in_flight.current.load(Ordering::Relaxed), 2);
+
+        drop(second);
+        drop(third);
+        let _fourth = in_flight.enter();
+
+        assert_eq!(in_flight.current.load(Ordering::Relaxed), 1);
+        assert_eq!(in_flight.peak.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn takes_turns_between_local_addresses_of_the_same_family() {
+        let bind: Vec<IpAddr> = ["10.0.0.1", "::1", "10.0.0.2", "::2"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let v4: SocketAddr = "10.0.1.1:80".parse().unwrap();
+        let v6: SocketAddr = "[::3]:80".parse().unwrap();
+
+        let picks: Vec<String> = (0..3)
+            .map(|turn| local_for(&bind, &v4, turn).unwrap().to_string())
+            .collect();
+        assert_eq!(picks, ["10.0.0.1", "10.0.0.2", "10.0.0.1"]);
+
+        let picks: Vec<String> = (0..3)
+            .map(|turn| local_for(&bind, &v6, turn).unwrap().to_string())
+            .collect();
+        assert_eq!(picks, ["::1", "::2", "::1"]);
+
+        let err = local_for(&bind[..1], &v6, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no bind address of the same family as [::3]:80"
+        );
+    }
+
+    #[test]
+    fn keeps_fixed_delays() {
+        let policy = ReconnectPolicy::default();
+        for failed in [0, 1, 5, usize::MAX] {
+            assert_eq!(policy.delay(failed), Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn doubles_exponential_delays_up_to_the_max() {
+        let policy = exponential(100, 1000);
+        let delays: Vec<u128> = (1..=6).map(|n| policy.delay(n).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(64), Duration::from_millis(1000));
+        assert_eq!(policy.delay(usize::MAX), Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_max_attempts() {
+        let policy = ReconnectPolicy {
+            backoff: Backoff::Fixed(Duration::ZERO),
+            max_attempts: Some(3),
+        };
+        let mut failures = 0;
+        let mut attempts = 0;
+
+        let result: Result<(), _> = retry(
+            Instant::now(),
+            Duration::from_secs(10),
+            &policy,
+            &mut failures,
+            || {
+                attempts += 1;
+                async { Err::<(), AnyError>("refused".into()) }
+            },
+        )
+        .await;
+
+        let err = result.unwrap_err().to_string();
+        assert_eq!(err, "gave up connecting after 3 attempts: refused");
+        assert_eq!((attempts, failures), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn retries_until_connected() {
+        let policy = ReconnectPolicy {
+            backoff: Backoff::Fixed(Duration::ZERO),
+            max_attempts: Some(5),
+        };
+        let mut failures = 0;
+        let mut attempts = 0;
+
+        let result = retry(
+            Instant::now(),
+            Duration::from_secs(10),
+            &policy,
+            &mut failures,
+            || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    match attempt {
+                        3 => Ok(attempt),
+                        _ => Err::<_, AnyError>("refused".into()),
+                    }
+                }
+            },
+        )
+        .await;
 
-        Ok(connection)
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(failures, 2);
     }
 }
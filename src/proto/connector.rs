@@ -1,101 +1,803 @@
 use crate::error::AnyError;
+use crate::proto::h2c::{self, UpgradeRequest};
 use crate::proto::protocol::HttpProtocol;
-use crate::proto::tls;
+use crate::proto::proxy::Tunnel;
+use crate::proto::tcp_stream::{ByteCounter, CustomTcpStream, FrameWatch};
+use crate::proto::tls::{self, TlsHandshake, TlsOptions};
+use crate::proto::uri::Address;
 use crate::utils::BoxedFuture;
 
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::task::JoinHandle;
 
-use rustls::ServerName;
+use rustls::pki_types::ServerName;
+use socket2::{SockRef, TcpKeepalive};
 use tokio_rustls::TlsConnector;
 
+use http::header::{HeaderValue, HOST};
+use hyper::body::Bytes;
 use hyper::client::conn;
-use hyper::Body;
+use hyper::{Body, Request, Response, Uri};
+
+use tower::{Service, ServiceExt};
+
+/// A future resolving to the response of a dispatched request.
+pub type ResponseFuture = BoxedFuture<'static, Result<Response<Body>, AnyError>>;
+
+/// Sends requests over an established connection.
+pub enum Sender {
+    /// A HTTP/1 connection driven by hyper.
+    Http1(conn::SendRequest<Body>),
+
+    /// A HTTP/2 connection driven by hyper.
+    Http2(conn::SendRequest<Body>),
+
+    /// A HTTP/2 connection upgraded from HTTP/1.1, driven by h2 directly
+    /// as the upgrade request takes the first stream.
+    H2c(h2::client::SendRequest<Bytes>),
+
+    /// A HTTP/3 connection over QUIC.
+    #[cfg(feature = "http3")]
+    Http3(crate::proto::quic::Http3Sender),
+}
+
+impl Sender {
+    /// Waits until the connection can take another request, returning a
+    /// future sending it and resolving to the response once its head
+    /// arrives. The body is streamed on every protocol, so latencies
+    /// measured from here mean the same thing everywhere.
+    pub async fn dispatch(&mut self, mut req: Request<Body>) -> Result<ResponseFuture, AnyError> {
+        match self {
+            Self::Http1(send_request) => {
+                // Requests built for either protocol are sent in origin
+                // form when the protocol is negotiated per connection.
+                if let Some(authority) = req.uri().authority() {
+                    if !req.headers().contains_key(HOST) {
+                        let host = HeaderValue::from_str(authority.as_str())?;
+                        req.headers_mut().insert(HOST, host);
+                    }
+
+                    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                    *req.uri_mut() = Uri::try_from(path)?;
+                }
+
+                send_hyper(send_request, req).await
+            }
+            Self::Http2(send_request) => send_hyper(send_request, req).await,
+            Self::H2c(send_request) => h2c::dispatch(send_request, req).await,
+            #[cfg(feature = "http3")]
+            Self::Http3(send_request) => send_request.dispatch(req).await,
+        }
+    }
+
+    /// The HTTP version spoken on the connection.
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Self::Http1(_) => "HTTP/1.1",
+            Self::Http2(_) | Self::H2c(_) => "HTTP/2",
+            #[cfg(feature = "http3")]
+            Self::Http3(_) => "HTTP/3",
+        }
+    }
+}
+
+async fn send_hyper(
+    send_request: &mut conn::SendRequest<Body>,
+    req: Request<Body>,
+) -> Result<ResponseFuture, AnyError> {
+    send_request.ready().await?;
+    let resp = send_request.call(req);
+
+    Ok(Box::pin(async move { Ok(resp.await?) }))
+}
+
+/// Tuning for HTTP/2 connections, anything left unset uses hyper's
+/// defaults.
+#[derive(Clone, Debug, Default)]
+pub struct Http2Options {
+    /// The initial flow control window of each stream in bytes.
+    pub initial_stream_window: Option<u32>,
+
+    /// The initial flow control window of the connection in bytes.
+    pub initial_connection_window: Option<u32>,
+
+    /// Sizes the windows from the measured bandwidth-delay product,
+    /// overriding the initial window sizes.
+    pub adaptive_window: bool,
+
+    /// The largest frame payload the server may send in bytes.
+    pub max_frame_size: Option<u32>,
+
+    /// How often to send keep-alive pings, disabled when unset.
+    pub keep_alive_interval: Option<Duration>,
+
+    /// How long to wait for a keep-alive ping to be acknowledged before
+    /// closing the connection.
+    pub keep_alive_timeout: Option<Duration>,
+
+    /// Sends keep-alive pings even when no requests are in flight.
+    pub keep_alive_while_idle: bool,
+}
+
+impl Http2Options {
+    fn apply_hyper(&self, builder: &mut conn::Builder) {
+        builder
+            .http2_initial_stream_window_size(self.initial_stream_window)
+            .http2_initial_connection_window_size(self.initial_connection_window)
+            .http2_adaptive_window(self.adaptive_window)
+            .http2_max_frame_size(self.max_frame_size)
+            .http2_keep_alive_interval(self.keep_alive_interval)
+            .http2_keep_alive_while_idle(self.keep_alive_while_idle);
+
+        if let Some(timeout) = self.keep_alive_timeout {
+            builder.http2_keep_alive_timeout(timeout);
+        }
+    }
+
+    /// Applies the settings h2 supports directly, keep-alive pings and
+    /// adaptive windows are implemented by hyper so don't apply to h2c
+    /// upgraded connections.
+    pub(crate) fn apply_h2(&self, builder: &mut h2::client::Builder) {
+        if let Some(size) = self.initial_stream_window {
+            builder.initial_window_size(size);
+        }
+
+        if let Some(size) = self.initial_connection_window {
+            builder.initial_connection_window_size(size);
+        }
+
+        if let Some(size) = self.max_frame_size {
+            builder.max_frame_size(size);
+        }
+    }
+}
+
+/// Socket options applied to TCP connections, anything left unset uses
+/// the OS defaults.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm so small requests are sent right away.
+    pub nodelay: bool,
+
+    /// The size of the socket's send buffer (SO_SNDBUF) in bytes.
+    pub send_buffer_size: Option<u32>,
+
+    /// The size of the socket's receive buffer (SO_RCVBUF) in bytes.
+    pub recv_buffer_size: Option<u32>,
+
+    /// How long the connection is idle before TCP keepalive probes are
+    /// sent, disabled when unset.
+    pub keepalive: Option<Duration>,
+
+    /// Resets connections when they're closed instead of going through
+    /// TIME_WAIT, by setting SO_LINGER to 0.
+    pub linger_zero: bool,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            keepalive: None,
+            linger_zero: false,
+        }
+    }
+}
+
+/// How a new connection reaches the server.
+#[derive(Clone, Debug, Default)]
+pub struct Route {
+    /// The local address to connect from, if any.
+    pub local: Option<IpAddr>,
+
+    pub tcp: TcpOptions,
+
+    /// The proxy tunnel opened before talking to the server, if any.
+    pub tunnel: Option<Arc<Tunnel>>,
+}
 
 pub struct Connection {
-    pub send_request: conn::SendRequest<Body>,
-    pub handle: JoinHandle<()>,
+    pub send_request: Sender,
+
+    /// The task driving the connection, finishing with the reason the
+    /// connection closed.
+    pub handle: JoinHandle<CloseReason>,
+
+    /// The TLS handshake made when connecting, if any.
+    pub tls: Option<TlsHandshake>,
+}
+
+/// Why a connection closed while the benchmark was running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The server closed the connection cleanly.
+    ServerClosed,
+
+    /// The server sent a HTTP/2 `GOAWAY`, either shutting down gracefully
+    /// or with an error code.
+    GoAway,
+
+    /// The connection failed.
+    Error,
+
+    /// A DNS refresh found the host no longer has the connection's
+    /// address, so it was closed to follow the change.
+    AddressChanged,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ServerClosed => "server closed",
+            Self::GoAway => "GOAWAY",
+            Self::Error => "error",
+            Self::AddressChanged => "address changed",
+        }
+    }
+
+    /// Works out why a connection driven by hyper finished, given if a
+    /// `GOAWAY` was read on it.
+    fn of_hyper(result: Result<(), hyper::Error>, go_away: bool) -> Self {
+        let e = match result {
+            Ok(()) => return Self::of_h2(Ok(()), go_away),
+            Err(e) => e,
+        };
+
+        match std::error::Error::source(&e).and_then(|e| e.downcast_ref::<h2::Error>()) {
+            Some(e) => Self::of_h2(Err(e), go_away),
+            None => Self::Error,
+        }
+    }
+
+    /// Works out why a connection driven by h2 finished, given if a
+    /// `GOAWAY` was read on it. A graceful `GOAWAY` finishes the
+    /// connection without an error, the same as the server closing it.
+    pub fn of_h2(result: Result<(), &h2::Error>, go_away: bool) -> Self {
+        match result {
+            Ok(()) if go_away => Self::GoAway,
+            Ok(()) => Self::ServerClosed,
+            Err(e) if e.is_go_away() && e.is_remote() => Self::GoAway,
+            Err(_) => Self::Error,
+        }
+    }
+}
+
+/// A connection without a HTTP client, for when requests are written
+/// directly.
+pub struct RawConnection {
+    pub stream: Box<dyn AsyncStream>,
+
+    /// The TLS handshake made when connecting, if any.
+    pub tls: Option<TlsHandshake>,
 }
 
+/// A connection's byte stream, without any HTTP client on top.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
 pub trait Connect {
-    fn handshake<S, P>(&self, stream: S, protocol: P) -> BoxedFuture<Result<Connection, AnyError>>
+    fn handshake<S, P>(
+        &self,
+        stream: S,
+        protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
         P: HttpProtocol + Send + Sync + 'static;
+
+    /// Sets up the stream without a HTTP client, for when requests are
+    /// written directly e.g. when pipelining.
+    fn handshake_raw<S>(&self, stream: S) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Opens a new connection to the given address along the route,
+    /// adding the amount of data read and written to the counter.
+    fn connect<P>(
+        &self,
+        addr: Address,
+        route: Route,
+        counter: Arc<ByteCounter>,
+        protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
+    where
+        Self: Sync,
+        P: HttpProtocol + Send + Sync + 'static,
+    {
+        Box::pin(async move {
+            match addr {
+                Address::Tcp(addr) => {
+                    let stream = connect_tcp(addr, &route).await?;
+                    let stream = CustomTcpStream::new(stream, counter);
+
+                    self.handshake(stream, protocol).await
+                }
+                Address::Unix(path) => {
+                    let stream = connect_unix(&path).await?;
+                    let stream = CustomTcpStream::new(stream, counter);
+
+                    self.handshake(stream, protocol).await
+                }
+            }
+        })
+    }
+
+    /// Opens a new connection to the given address without a HTTP client.
+    fn connect_raw(
+        &self,
+        addr: Address,
+        route: Route,
+        counter: Arc<ByteCounter>,
+    ) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        Self: Sync,
+    {
+        Box::pin(async move {
+            match addr {
+                Address::Tcp(addr) => {
+                    let stream = connect_tcp(addr, &route).await?;
+                    let stream = CustomTcpStream::new(stream, counter);
+
+                    self.handshake_raw(stream).await
+                }
+                Address::Unix(path) => {
+                    let stream = connect_unix(&path).await?;
+                    let stream = CustomTcpStream::new(stream, counter);
+
+                    self.handshake_raw(stream).await
+                }
+            }
+        })
+    }
+}
+
+/// Connects over TCP, binding the socket to the local address first when
+/// one is given and opening the proxy tunnel once connected.
+///
+/// The buffer sizes are set before connecting so the window scale
+/// negotiated in the handshake can make use of them.
+async fn connect_tcp(addr: SocketAddr, route: &Route) -> Result<TcpStream, AnyError> {
+    let options = route.tcp;
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    if let Some(local) = route.local {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    let mut stream = socket.connect(addr).await?;
+    stream.set_nodelay(options.nodelay)?;
+
+    let sock = SockRef::from(&stream);
+    if let Some(time) = options.keepalive {
+        sock.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+
+    if options.linger_zero {
+        sock.set_linger(Some(Duration::ZERO))?;
+    }
+
+    if let Some(tunnel) = route.tunnel.as_ref() {
+        tunnel.open(&mut stream).await?;
+    }
+
+    Ok(stream)
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> Result<UnixStream, AnyError> {
+    Ok(UnixStream::connect(path).await?)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &Path) -> Result<TcpStream, AnyError> {
+    Err("unix sockets are not supported on this platform".into())
 }
 
-pub struct HttpConnector;
+#[derive(Default)]
+pub struct HttpConnector {
+    http2: Http2Options,
+}
 
 impl HttpConnector {
-    pub fn new() -> Self {
-        Self
+    pub fn new(http2: Http2Options) -> Self {
+        Self { http2 }
     }
 }
 
 impl Connect for HttpConnector {
-    fn handshake<S, P>(&self, stream: S, protocol: P) -> BoxedFuture<Result<Connection, AnyError>>
+    fn handshake<S, P>(
+        &self,
+        stream: S,
+        protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
         P: HttpProtocol + Send + Sync + 'static,
     {
-        Box::pin(handshake(stream, protocol))
+        Box::pin(handshake(stream, protocol.use_http2(None), &self.http2))
+    }
+
+    fn handshake_raw<S>(&self, stream: S) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Box::pin(async move {
+            Ok(RawConnection {
+                stream: Box::new(stream),
+                tls: None,
+            })
+        })
     }
 }
 
 pub struct HttpsConnector {
-    domain: ServerName,
+    domain: ServerName<'static>,
     tls_connector: TlsConnector,
+    http2: Http2Options,
 }
 
 impl HttpsConnector {
-    pub fn new(domain: &str, alpn: &[Vec<u8>]) -> Result<Self, AnyError> {
-        let domain = ServerName::try_from(domain)?;
-        let tls_connector = tls::connector_from_alpn(alpn)?;
+    pub fn new(
+        domain: &str,
+        alpn: &[Vec<u8>],
+        http2: Http2Options,
+        tls: &TlsOptions,
+    ) -> Result<Self, AnyError> {
+        let domain = ServerName::try_from(tls.server_name(domain).to_string())?;
+        let tls_connector = tls::connector_from_alpn(alpn, tls)?;
 
         Ok(Self {
             domain,
             tls_connector,
+            http2,
         })
     }
 }
 
 impl Connect for HttpsConnector {
-    fn handshake<S, P>(&self, stream: S, protocol: P) -> BoxedFuture<Result<Connection, AnyError>>
+    fn handshake<S, P>(
+        &self,
+        stream: S,
+        protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
         P: HttpProtocol + Send + Sync + 'static,
     {
         Box::pin(async move {
+            let start = Instant::now();
+            let stream = self
+                .tls_connector
+                .connect(self.domain.clone(), stream)
+                .await?;
+            let tls = TlsHandshake::new(start.elapsed(), stream.get_ref().1);
+
+            let http2 = protocol.use_http2(stream.get_ref().1.alpn_protocol());
+            let mut connection = handshake(stream, http2, &self.http2).await?;
+            connection.tls = Some(tls);
+
+            Ok(connection)
+        })
+    }
+
+    fn handshake_raw<S>(&self, stream: S) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Box::pin(async move {
+            let start = Instant::now();
             let stream = self
                 .tls_connector
                 .connect(self.domain.clone(), stream)
                 .await?;
+            let tls = TlsHandshake::new(start.elapsed(), stream.get_ref().1);
 
-            handshake(stream, protocol).await
+            Ok(RawConnection {
+                stream: Box::new(stream),
+                tls: Some(tls),
+            })
         })
     }
 }
 
-async fn handshake<S, P>(stream: S, protocol: P) -> Result<Connection, AnyError>
+/// Connects over cleartext HTTP/2 by upgrading from HTTP/1.1 instead of
+/// relying on prior knowledge.
+pub struct H2cUpgradeConnector {
+    upgrade: UpgradeRequest,
+    http2: Http2Options,
+}
+
+impl H2cUpgradeConnector {
+    pub fn new(upgrade: UpgradeRequest, http2: Http2Options) -> Self {
+        Self { upgrade, http2 }
+    }
+}
+
+impl Connect for H2cUpgradeConnector {
+    fn handshake<S, P>(
+        &self,
+        stream: S,
+        _protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        P: HttpProtocol + Send + Sync + 'static,
+    {
+        Box::pin(h2c::upgrade(stream, &self.upgrade, &self.http2))
+    }
+
+    fn handshake_raw<S>(&self, _stream: S) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Box::pin(async { Err("h2c upgraded connections are always HTTP/2".into()) })
+    }
+}
+
+async fn handshake<S>(
+    stream: S,
+    http2: bool,
+    options: &Http2Options,
+) -> Result<Connection, AnyError>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    P: HttpProtocol + Send + Sync + 'static,
 {
-    let (send_request, connection) = conn::Builder::new()
-        .http2_only(protocol.is_http2())
-        .handshake(stream)
-        .await?;
+    let mut builder = conn::Builder::new();
+    builder.http2_only(http2);
+    options.apply_hyper(&mut builder);
+
+    let (send_request, handle) = if http2 {
+        let go_away = Arc::new(AtomicBool::new(false));
+        let stream = FrameWatch::new(stream, go_away.clone());
+        let (send_request, connection) = builder.handshake(stream).await?;
 
-    let handle = tokio::spawn(async move {
-        // TODO: handle error
-        let _ = connection.await;
+        let handle = tokio::spawn(async move {
+            let result = connection.await;
+            CloseReason::of_hyper(result, go_away.load(Ordering::Acquire))
+        });
 
-        // Connection died
-        // Should reconnect and log
-    });
+        (Sender::Http2(send_request), handle)
+    } else {
+        let (send_request, connection) = builder.handshake(stream).await?;
+        let handle = tokio::spawn(async move { CloseReason::of_hyper(connection.await, false) });
+
+        (Sender::Http1(send_request), handle)
+    };
 
     Ok(Connection {
         send_request,
         handle,
+        tls: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proto::{Http1, HttpAuto};
+
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+
+    /// Accepts a single TLS connection over an in-memory stream, picking
+    /// from the protocols offered with ALPN in the order given.
+    fn tls_server(alpn: &[&[u8]]) -> tokio::io::DuplexStream {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let chain = vec![CertificateDer::from(cert.cert.der().to_vec())];
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key.into())
+            .unwrap();
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut stream = TlsAcceptor::from(Arc::new(config))
+                .accept(server)
+                .await
+                .unwrap();
+
+            // Keeps the connection open until the client is done.
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        client
+    }
+
+    async fn negotiate<P>(server_alpn: &[&[u8]], protocol: P) -> Connection
+    where
+        P: HttpProtocol + Send + Sync + 'static,
+    {
+        let options = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        let connector = HttpsConnector::new(
+            "localhost",
+            &protocol.alpn_protocols(),
+            Http2Options::default(),
+            &options,
+        )
+        .unwrap();
+
+        connector
+            .handshake(tls_server(server_alpn), protocol)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn speaks_the_protocol_chosen_with_alpn() {
+        let conn = negotiate(&[b"h2", b"http/1.1"], HttpAuto).await;
+        assert!(matches!(conn.send_request, Sender::Http2(_)));
+        assert!(conn.tls.is_some());
+
+        let conn = negotiate(&[b"http/1.1"], HttpAuto).await;
+        assert!(matches!(conn.send_request, Sender::Http1(_)));
+
+        // Servers without ALPN get HTTP/1.1 too.
+        let conn = negotiate(&[], HttpAuto).await;
+        assert!(matches!(conn.send_request, Sender::Http1(_)));
+
+        let conn = negotiate(&[b"h2", b"http/1.1"], Http1).await;
+        assert!(matches!(conn.send_request, Sender::Http1(_)));
+    }
+
+    #[tokio::test]
+    async fn sends_negotiated_http1_requests_in_origin_form() {
+        let (client, mut server) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(server.read_u8().await.unwrap());
+            }
+
+            server
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let mut conn = handshake(client, false, &Http2Options::default())
+            .await
+            .unwrap();
+        assert_eq!(conn.send_request.protocol(), "HTTP/1.1");
+
+        let req = Request::get("https://example.com:8443/a?b=1")
+            .body(Body::empty())
+            .unwrap();
+        let resp = conn
+            .send_request
+            .dispatch(req)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 204);
+
+        let head = server.await.unwrap().to_lowercase();
+        assert!(head.starts_with("get /a?b=1 http/1.1\r\n"));
+        assert!(head.contains("\r\nhost: example.com:8443\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connects_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("rewrk-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+
+            let resp = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+            stream.write_all(resp).await.unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let counter = Arc::new(ByteCounter::default());
+        let mut conn = HttpConnector::new(Http2Options::default())
+            .connect(
+                Address::Unix(path.clone()),
+                Route::default(),
+                counter.clone(),
+                Http1,
+            )
+            .await
+            .unwrap();
+
+        let req = Request::get("/")
+            .header(HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let resp = conn
+            .send_request
+            .dispatch(req)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "ok");
+        assert!(counter.read.load(Ordering::Acquire) > body.len());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_tcp_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let route = Route {
+            tcp: TcpOptions {
+                nodelay: false,
+                send_buffer_size: Some(64 * 1024),
+                recv_buffer_size: Some(128 * 1024),
+                keepalive: Some(Duration::from_secs(30)),
+                linger_zero: true,
+            },
+            ..Default::default()
+        };
+        let stream = connect_tcp(addr, &route).await.unwrap();
+        let sock = SockRef::from(&stream);
+
+        assert!(!stream.nodelay().unwrap());
+        assert!(sock.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(sock.recv_buffer_size().unwrap() >= 128 * 1024);
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(sock.linger().unwrap(), Some(Duration::ZERO));
+
+        let stream = connect_tcp(addr, &Route::default()).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+        assert_eq!(SockRef::from(&stream).linger().unwrap(), None);
+    }
+
+    #[test]
+    fn tells_graceful_go_aways_apart() {
+        assert_eq!(CloseReason::of_h2(Ok(()), false), CloseReason::ServerClosed);
+        assert_eq!(CloseReason::of_h2(Ok(()), true), CloseReason::GoAway);
+
+        // Only a GOAWAY from the server counts, not a local error.
+        let err = h2::Error::from(h2::Reason::ENHANCE_YOUR_CALM);
+        assert_eq!(CloseReason::of_h2(Err(&err), true), CloseReason::Error);
+    }
+}
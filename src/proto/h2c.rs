@@ -0,0 +1,379 @@
+use crate::error::AnyError;
+use crate::proto::connector::{CloseReason, Connection, Http2Options, ResponseFuture, Sender};
+use crate::proto::tcp_stream::FrameWatch;
+use crate::proto::Target;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+
+use futures_util::stream;
+
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+/// The most data read while waiting for the upgrade response.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// The base64url encoded SETTINGS payload sent with the upgrade request,
+/// disabling server push.
+const HTTP2_SETTINGS: &str = "AAIAAAAA";
+
+/// The headers of a target replaced by the upgrade's own.
+const UPGRADE_HEADERS: [HeaderName; 5] = [
+    header::HOST,
+    header::CONNECTION,
+    header::UPGRADE,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+];
+
+/// The HTTP/1.1 request a connection is upgraded with, taken from the
+/// first target so the server sees the method, path and Host being
+/// benchmarked rather than a stand-in request.
+///
+/// Templated parts of the target are sent unrendered.
+#[derive(Clone)]
+pub struct UpgradeRequest {
+    method: Method,
+    path: String,
+    host: String,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl UpgradeRequest {
+    pub fn new(target: &Target) -> Self {
+        let uri = &target.uri;
+        let host = match target.headers.get(header::HOST) {
+            Some(host) => String::from_utf8_lossy(host.as_bytes()).into_owned(),
+            None => uri
+                .authority()
+                .map_or_else(|| uri.host().unwrap_or_default(), |a| a.as_str())
+                .to_string(),
+        };
+
+        Self {
+            method: target.method.clone(),
+            path: uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            host,
+            headers: target.headers.clone(),
+            body: target.body.clone(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut req = format!(
+            "{} {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\n\
+             HTTP2-Settings: {}\r\n",
+            self.method, self.path, self.host, HTTP2_SETTINGS,
+        )
+        .into_bytes();
+
+        for (k, v) in &self.headers {
+            if UPGRADE_HEADERS.contains(k) || k == "http2-settings" {
+                continue;
+            }
+
+            req.extend_from_slice(k.as_str().as_bytes());
+            req.extend_from_slice(b": ");
+            req.extend_from_slice(v.as_bytes());
+            req.extend_from_slice(b"\r\n");
+        }
+
+        if !self.body.is_empty() {
+            req.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+
+        req.extend_from_slice(b"\r\n");
+        req.extend_from_slice(&self.body);
+        req
+    }
+}
+
+/// Upgrades a cleartext HTTP/1.1 connection to h2c with the
+/// `Upgrade: h2c` handshake, rather than assuming prior knowledge.
+///
+/// The server answers the upgrade request as the first HTTP/2 stream,
+/// the benchmark's requests start from the next stream and the server's
+/// response on the first one is discarded.
+pub async fn upgrade<S>(
+    mut stream: S,
+    req: &UpgradeRequest,
+    options: &Http2Options,
+) -> Result<Connection, AnyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    stream.write_all(&req.encode()).await?;
+
+    // The response head is read a byte at a time so none of the HTTP/2
+    // frames following it are consumed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err("h2c upgrade response head is too large".into());
+        }
+
+        let byte = stream.read_u8().await?;
+        head.push(byte);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(&head)?;
+
+    let status = resp.code.unwrap_or_default();
+    if status != StatusCode::SWITCHING_PROTOCOLS.as_u16() {
+        return Err(format!("server refused the h2c upgrade with status {}", status).into());
+    }
+
+    let mut builder = h2::client::Builder::new();
+    builder.initial_stream_id(3);
+    options.apply_h2(&mut builder);
+
+    let go_away = Arc::new(AtomicBool::new(false));
+    let (answered, upgrade_response) = oneshot::channel();
+    let stream = FrameWatch::new(stream, go_away.clone()).with_upgrade_response(answered);
+    let (send_request, connection) = builder.handshake::<_, Bytes>(stream).await?;
+
+    let handle = tokio::spawn(async move {
+        let result = connection.await;
+        CloseReason::of_h2(result.as_ref().copied(), go_away.load(Ordering::Acquire))
+    });
+
+    // h2 takes the first stream for one opened before `initial_stream_id`
+    // and discards the response on it: the headers are still decoded,
+    // keeping the HPACK state in sync, before the stream is reset and
+    // any of its data is dropped with the flow control capacity released.
+    // Waiting for that response makes sure it's out of the way before
+    // the benchmark's requests are sent.
+    if upgrade_response.await.is_err() {
+        return Err("h2c connection closed before the upgrade response".into());
+    }
+
+    Ok(Connection {
+        send_request: Sender::H2c(send_request),
+        handle,
+        tls: None,
+    })
+}
+
+/// Waits until the upgraded h2c connection can open another stream,
+/// returning a future sending the request and resolving to the response
+/// once its head arrives, with the body streamed like hyper's.
+pub async fn dispatch(
+    send_request: &mut h2::client::SendRequest<Bytes>,
+    req: Request<Body>,
+) -> Result<ResponseFuture, AnyError> {
+    let mut send_request = send_request.clone().ready().await?;
+
+    Ok(Box::pin(async move {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        let (resp, mut stream) =
+            send_request.send_request(Request::from_parts(parts, ()), body.is_empty())?;
+        if !body.is_empty() {
+            stream.send_data(body, true)?;
+        }
+
+        let (parts, recv) = resp.await?.into_parts();
+
+        let body = stream::unfold(recv, |mut recv| async move {
+            let chunk = recv.data().await?.and_then(|chunk| {
+                recv.flow_control().release_capacity(chunk.len())?;
+                Ok(chunk)
+            });
+            Some((chunk, recv))
+        });
+
+        Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, BufReader, DuplexStream};
+
+    const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, flags];
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    async fn read_frame(stream: &mut BufReader<DuplexStream>) -> (u8, u32) {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).await.unwrap();
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        let [_, _, _, kind, _, id @ ..] = header;
+        (kind, u32::from_be_bytes(id))
+    }
+
+    /// Reads a HTTP/1.1 request, returning its head and body.
+    async fn read_request(stream: &mut BufReader<DuplexStream>) -> (String, Vec<u8>) {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+
+        let len = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+
+        (head, body)
+    }
+
+    /// Stands in for a server going through with the upgrade. The response
+    /// on the first stream adds a header to the HPACK dynamic table, which
+    /// the response on the next stream refers to, so it's only decoded
+    /// right if the first response went through h2.
+    async fn upgrading_server(stream: DuplexStream) -> (String, Vec<u8>) {
+        let mut stream = BufReader::new(stream);
+        let req = read_request(&mut stream).await;
+
+        let mut resp = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: h2c\r\n\r\n"
+            .to_vec();
+        resp.extend(frame(0x4, 0, 0, &[]));
+        // `:status: 200` then `x-upgraded: yes` added to the dynamic table.
+        resp.extend(frame(0x1, 0x4, 1, b"\x88\x40\x0ax-upgraded\x03yes"));
+        resp.extend(frame(0x0, 0x1, 1, b"the upgrade response"));
+        stream.write_all(&resp).await.unwrap();
+
+        let mut preface = [0; PREFACE.len()];
+        stream.read_exact(&mut preface).await.unwrap();
+        assert_eq!(preface, PREFACE);
+
+        loop {
+            match read_frame(&mut stream).await {
+                (0x1, 3) => break,
+                (0x1, id) => panic!("unexpected request on stream {}", id),
+                _ => {}
+            }
+        }
+
+        // `:status: 200` and the dynamic table's only entry.
+        let resp = frame(0x1, 0x5, 3, b"\x88\xbe");
+        stream.write_all(&resp).await.unwrap();
+
+        // Keeps the connection open until the client is done.
+        let _ = stream.read_to_end(&mut Vec::new()).await;
+        req
+    }
+
+    fn upgrade_request() -> UpgradeRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-token", "abc".parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, "1".parse().unwrap());
+
+        UpgradeRequest {
+            method: Method::POST,
+            path: "/upload?id=1".to_string(),
+            host: "example.com".to_string(),
+            headers,
+            body: Bytes::from_static(b"hello"),
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_with_the_target_request() {
+        let (client, server) = duplex(64 * 1024);
+        let server = tokio::spawn(upgrading_server(server));
+
+        let conn = upgrade(client, &upgrade_request(), &Http2Options::default())
+            .await
+            .unwrap();
+        let mut sender = match conn.send_request {
+            Sender::H2c(sender) => sender,
+            _ => panic!("expected a h2c connection"),
+        };
+
+        let req = Request::get("http://example.com/next")
+            .body(Body::empty())
+            .unwrap();
+        let resp = dispatch(&mut sender, req).await.unwrap().await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-upgraded"], "yes");
+
+        drop(sender);
+        conn.handle.abort();
+        let (head, body) = server.await.unwrap();
+
+        assert!(head.starts_with("POST /upload?id=1 HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(head.contains("\r\nUpgrade: h2c\r\n"));
+        assert!(head.contains("\r\nx-token: abc\r\n"));
+        assert!(head.contains("\r\nContent-Length: 5\r\n"));
+        assert!(!head.contains("content-length: 1"));
+        assert_eq!(body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_upgrade_is_refused() {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(server);
+            read_request(&mut stream).await;
+
+            let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(resp).await.unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let err = upgrade(client, &upgrade_request(), &Http2Options::default())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "server refused the h2c upgrade with status 200"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_when_closed_before_the_upgrade_response() {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(server);
+            read_request(&mut stream).await;
+
+            let mut resp = b"HTTP/1.1 101 Switching Protocols\r\n\r\n".to_vec();
+            resp.extend(frame(0x4, 0, 0, &[]));
+            stream.write_all(&resp).await.unwrap();
+
+            let mut preface = [0; PREFACE.len()];
+            stream.read_exact(&mut preface).await.unwrap();
+        });
+
+        let err = upgrade(client, &upgrade_request(), &Http2Options::default())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "h2c connection closed before the upgrade response"
+        );
+    }
+}
@@ -3,11 +3,29 @@ pub mod tls;
 
 pub mod client;
 pub mod connector;
+pub mod h2c;
 pub mod parse;
+pub mod pipeline;
 pub mod protocol;
+pub mod proxy;
+#[cfg(feature = "http3")]
+pub mod quic;
+pub mod request;
+pub mod template;
 pub mod uri;
 
-pub use client::{BenchmarkClient, Client};
-pub use connector::{Connect, Connection, HttpConnector, HttpsConnector};
-pub use protocol::{Http1, Http2, HttpProtocol};
-pub use uri::{ParsedUri, Scheme};
+pub use client::{Backoff, BenchmarkClient, Client, ClientOptions, ReconnectPolicy};
+pub use connector::{
+    AsyncStream, CloseReason, Connect, Connection, H2cUpgradeConnector, Http2Options,
+    HttpConnector, HttpsConnector, RawConnection, Route, Sender, TcpOptions,
+};
+pub use h2c::UpgradeRequest;
+#[cfg(feature = "http3")]
+pub use protocol::Http3;
+pub use protocol::{Http1, Http2, HttpAuto, HttpProtocol};
+pub use proxy::{Proxy, ProxyKind, Tunnel};
+#[cfg(feature = "http3")]
+pub use quic::QuicConnector;
+pub use request::{ReplayOrder, RequestSpec};
+pub use tls::{TlsHandshake, TlsOptions, TlsVersion};
+pub use uri::{AddrStrategy, Address, LookupOptions, ParsedUri, Resolve, Scheme, Target};
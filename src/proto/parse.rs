@@ -1,39 +1,40 @@
-use http::HeaderMap;
-
+use crate::bench::BenchmarkSettings;
 use crate::error::AnyError;
 use crate::http::BenchType;
 use crate::proto::{
-    BenchmarkClient, Client, Connect, Http1, Http2, HttpConnector, HttpProtocol, HttpsConnector,
-    ParsedUri, Scheme,
+    BenchmarkClient, Client, ClientOptions, Connect, H2cUpgradeConnector, Http1, Http2,
+    Http2Options, HttpAuto, HttpConnector, HttpProtocol, HttpsConnector, LookupOptions, ParsedUri,
+    RequestSpec, Scheme, TlsOptions, UpgradeRequest,
 };
+#[cfg(feature = "http3")]
+use crate::proto::{Http3, QuicConnector};
 
 use std::sync::Arc;
-use std::time::Duration;
 
 struct ClientBuilder {
-    time_for: Duration,
-    predicted_size: usize,
     parsed_uri: ParsedUri,
-    headers: HeaderMap,
+    options: ClientOptions,
+    http2: Http2Options,
+    tls: TlsOptions,
 }
 
 impl ClientBuilder {
     fn new(
-        time_for: Duration,
-        predicted_size: usize,
         parsed_uri: ParsedUri,
-        headers: HeaderMap,
+        options: ClientOptions,
+        http2: Http2Options,
+        tls: TlsOptions,
     ) -> Self {
         Self {
-            time_for,
-            predicted_size,
             parsed_uri,
-            headers,
+            options,
+            http2,
+            tls,
         }
     }
 
     fn uri_host(&self) -> &str {
-        self.parsed_uri.uri.host().unwrap()
+        &self.parsed_uri.host
     }
 
     fn uri_scheme(&self) -> Scheme {
@@ -45,32 +46,72 @@ impl ClientBuilder {
         C: Connect + Send + Sync + 'static,
         P: HttpProtocol + Copy + Send + Sync + 'static,
     {
-        BenchmarkClient::new(
-            connector,
-            protocol,
-            self.time_for,
-            self.predicted_size,
-            self.parsed_uri,
-            self.headers,
-        )
+        BenchmarkClient::new(connector, protocol, self.parsed_uri, self.options)
     }
 }
 
-pub async fn get_client(
-    time_for: Duration,
-    uri_string: String,
-    headers: HeaderMap,
-    bench_type: BenchType,
+/// Builds a client for each group of requests sharing an authority,
+/// paired with the total weight of the group.
+pub async fn get_clients(
+    settings: &BenchmarkSettings,
+    requests: &[RequestSpec],
     predicted_size: usize,
-) -> Result<Arc<dyn Client>, AnyError> {
-    let parsed_uri = ParsedUri::parse_and_lookup(&uri_string).await?;
-
-    let builder = ClientBuilder::new(time_for, predicted_size, parsed_uri, headers);
-
-    match bench_type {
-        BenchType::HTTP1 => build_http1(builder),
-        BenchType::HTTP2 => build_http2(builder),
+) -> Result<Vec<(Arc<dyn Client>, u64)>, AnyError> {
+    let data = settings.data.clone();
+    let lookup = LookupOptions {
+        unix_socket: settings.unix_socket.clone(),
+        strategy: settings.addr_strategy,
+        refresh: settings.dns_refresh,
+        resolve: settings.resolve.clone(),
+        proxy: settings.proxy.clone(),
+    };
+
+    let groups = ParsedUri::parse_and_lookup(
+        requests,
+        &settings.headers,
+        settings.replay_order,
+        data.as_deref(),
+        &lookup,
+    )
+    .await?;
+
+    let options = ClientOptions {
+        time_for: settings.duration,
+        predicted_size,
+        data,
+        script: settings.script.clone(),
+        streams: settings.streams,
+        pipeline: settings.pipeline,
+        bind: settings.bind.clone(),
+        tcp: settings.tcp,
+        proxy: settings.proxy.clone(),
+        reconnect: settings.reconnect,
+    };
+
+    let mut clients = Vec::with_capacity(groups.len());
+    for parsed_uri in groups {
+        parsed_uri.check_bind(&settings.bind)?;
+
+        let weight = parsed_uri.total_weight();
+        let builder = ClientBuilder::new(
+            parsed_uri,
+            options.clone(),
+            settings.http2.clone(),
+            settings.tls.clone(),
+        );
+
+        let client = match settings.bench_type {
+            BenchType::HTTP1 => build_http1(builder)?,
+            BenchType::HTTP2 => build_http2(builder)?,
+            BenchType::HTTP2Upgrade => build_http2_upgrade(builder)?,
+            BenchType::Auto => build_auto(builder)?,
+            BenchType::HTTP3 => build_http3(builder)?,
+        };
+
+        clients.push((client, weight));
     }
+
+    Ok(clients)
 }
 
 fn build_http1(builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
@@ -91,11 +132,54 @@ fn build_http2(builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
     }
 }
 
+fn build_http2_upgrade(builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
+    let protocol = Http2;
+
+    match builder.uri_scheme() {
+        Scheme::HTTP => {
+            let upgrade = UpgradeRequest::new(&builder.parsed_uri.targets[0]);
+            let connector = H2cUpgradeConnector::new(upgrade, builder.http2.clone());
+            Ok(Arc::new(builder.build(connector, protocol)))
+        }
+        // TLS connections negotiate h2 with ALPN instead.
+        Scheme::HTTPS => build_https(builder, protocol),
+    }
+}
+
+fn build_auto(builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
+    let protocol = HttpAuto;
+
+    match builder.uri_scheme() {
+        Scheme::HTTP => build_http(builder, protocol),
+        Scheme::HTTPS => build_https(builder, protocol),
+    }
+}
+
+#[cfg(feature = "http3")]
+fn build_http3(builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
+    let protocol = Http3;
+
+    if builder.uri_scheme() != Scheme::HTTPS {
+        return Err("HTTP/3 requires a https url".into());
+    }
+
+    let host = builder.uri_host().to_owned();
+    let connector = QuicConnector::new(&host, &protocol.alpn_protocols(), &builder.tls)?;
+
+    Ok(Arc::new(builder.build(connector, protocol)))
+}
+
+#[cfg(not(feature = "http3"))]
+fn build_http3(_builder: ClientBuilder) -> Result<Arc<dyn Client>, AnyError> {
+    Err("rewrk was built without HTTP/3 support, enable the http3 feature".into())
+}
+
 fn build_http<P>(builder: ClientBuilder, protocol: P) -> Result<Arc<dyn Client>, AnyError>
 where
     P: HttpProtocol + Copy + Send + Sync + 'static,
 {
-    Ok(Arc::new(builder.build(HttpConnector::new(), protocol)))
+    let connector = HttpConnector::new(builder.http2.clone());
+    Ok(Arc::new(builder.build(connector, protocol)))
 }
 
 fn build_https<P>(builder: ClientBuilder, protocol: P) -> Result<Arc<dyn Client>, AnyError>
@@ -103,9 +187,12 @@ where
     P: HttpProtocol + Copy + Send + Sync + 'static,
 {
     let host = builder.uri_host().to_owned();
-
-    Ok(Arc::new(builder.build(
-        HttpsConnector::new(&host, &protocol.alpn_protocols())?,
-        protocol,
-    )))
+    let connector = HttpsConnector::new(
+        &host,
+        &protocol.alpn_protocols(),
+        builder.http2.clone(),
+        &builder.tls,
+    )?;
+
+    Ok(Arc::new(builder.build(connector, protocol)))
 }
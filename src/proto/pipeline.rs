@@ -0,0 +1,354 @@
+use crate::error::AnyError;
+
+use std::io::{self, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use http::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, Method, StatusCode};
+use hyper::{Body, Request};
+
+/// The most headers a pipelined response may have.
+const MAX_HEADERS: usize = 64;
+
+/// The amount of data read from the connection at once.
+const READ_SIZE: usize = 8 * 1024;
+
+/// Encodes a request as HTTP/1.1 on to the end of the buffer, returning
+/// the length of its body.
+pub async fn encode_request(req: Request<Body>, buf: &mut Vec<u8>) -> Result<usize, AnyError> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+
+    write!(buf, "{} {} HTTP/1.1\r\n", parts.method, parts.uri)?;
+
+    for (name, value) in parts.headers.iter() {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    let has_body = matches!(parts.method, Method::POST | Method::PUT | Method::PATCH);
+    if has_body || !body.is_empty() {
+        write!(buf, "content-length: {}\r\n", body.len())?;
+    }
+
+    buf.extend_from_slice(b"\r\n");
+    buf.extend_from_slice(&body);
+
+    Ok(body.len())
+}
+
+/// A response read from a pipelined connection.
+#[derive(Debug)]
+pub struct PipelinedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+
+    /// If the server is closing the connection after this response.
+    pub close: bool,
+}
+
+/// Reads responses one after the other from a connection, keeping any
+/// data read past the end of a response for the next one.
+#[derive(Default)]
+pub struct ResponseReader {
+    buf: Vec<u8>,
+}
+
+impl ResponseReader {
+    /// Reads the response to a request sent with the given method, which
+    /// decides if the response can have a body.
+    pub async fn read_response<S>(
+        &mut self,
+        io: &mut S,
+        method: &Method,
+    ) -> Result<PipelinedResponse, AnyError>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        loop {
+            if let Some((resp, len)) = parse_response(&self.buf, method)? {
+                self.buf.drain(..len);
+
+                // Informational responses are followed by the real one.
+                if resp.status.is_informational() {
+                    continue;
+                }
+
+                return Ok(resp);
+            }
+
+            let filled = self.buf.len();
+            self.buf.resize(filled + READ_SIZE, 0);
+
+            let read = io.read(&mut self.buf[filled..]).await;
+            self.buf.truncate(filled + *read.as_ref().unwrap_or(&0));
+
+            if read? == 0 {
+                return Err(
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed").into(),
+                );
+            }
+        }
+    }
+}
+
+/// Parses a complete response to a request with the given method from the
+/// start of the buffer, returning it along with its length or `None` if
+/// more data is needed.
+fn parse_response(
+    buf: &[u8],
+    method: &Method,
+) -> Result<Option<(PipelinedResponse, usize)>, AnyError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut resp = httparse::Response::new(&mut headers);
+
+    let head_len = match resp.parse(buf)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let status = StatusCode::from_u16(resp.code.unwrap_or_default())?;
+
+    let mut header_map = HeaderMap::with_capacity(resp.headers.len());
+    for header in resp.headers.iter() {
+        header_map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+
+    let has_token = |name: HeaderName, token: &str| {
+        header_map.get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .unwrap_or_default()
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    let chunked = has_token(TRANSFER_ENCODING, "chunked");
+    let close = has_token(CONNECTION, "close");
+
+    let content_length = match header_map.get(CONTENT_LENGTH) {
+        Some(len) => Some(len.to_str()?.trim().parse::<usize>()?),
+        None => None,
+    };
+
+    let rest = &buf[head_len..];
+
+    // Responses to HEAD and successful CONNECT requests never have a body,
+    // whatever their headers say.
+    let no_body = status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || method == Method::HEAD
+        || (method == Method::CONNECT && status.is_success());
+
+    let (body, body_len) = if no_body {
+        (Vec::new(), 0)
+    } else if chunked {
+        match decode_chunked(rest)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        }
+    } else if let Some(len) = content_length {
+        if rest.len() < len {
+            return Ok(None);
+        }
+        (rest[..len].to_vec(), len)
+    } else {
+        return Err("pipelined responses must have a content-length or be chunked".into());
+    };
+
+    let resp = PipelinedResponse {
+        status,
+        headers: header_map,
+        body,
+        close,
+    };
+
+    Ok(Some((resp, head_len + body_len)))
+}
+
+/// Decodes a chunked body from the start of the buffer, returning the
+/// body and the length of its encoding or `None` if more data is needed.
+fn decode_chunked(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, AnyError> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let (len, size) = match httparse::parse_chunk_size(&buf[pos..]) {
+            Ok(httparse::Status::Complete(chunk)) => chunk,
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(_) => return Err("invalid chunk size".into()),
+        };
+        pos += len;
+
+        if size == 0 {
+            break;
+        }
+
+        let size = size as usize;
+        if buf.len() < pos + size + 2 {
+            return Ok(None);
+        }
+
+        if &buf[pos + size..pos + size + 2] != b"\r\n" {
+            return Err("chunk data is not followed by CRLF".into());
+        }
+
+        body.extend_from_slice(&buf[pos..pos + size]);
+        pos += size + 2;
+    }
+
+    // Skip any trailers up to the final empty line.
+    loop {
+        let line_end = match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(end) => pos + end,
+            None => return Ok(None),
+        };
+
+        let empty = line_end == pos;
+        pos = line_end + 2;
+
+        if empty {
+            return Ok(Some((body, pos)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    fn parse(buf: &[u8], method: Method) -> (PipelinedResponse, usize) {
+        parse_response(buf, &method).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_content_length_bodies() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let (resp, len) = parse(raw, Method::GET);
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.body, b"hello");
+        assert_eq!(len, raw.len());
+        assert!(!resp.close);
+    }
+
+    #[test]
+    fn parses_chunked_bodies_with_trailers() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: a\r\n\r\n";
+        let (resp, len) = parse(raw, Method::GET);
+        assert_eq!(resp.body, b"hello world");
+        assert_eq!(len, raw.len());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX0\r\n\r\n";
+        assert!(parse_response(raw, &Method::GET).is_err());
+
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(parse_response(raw, &Method::GET).is_err());
+    }
+
+    #[test]
+    fn parses_bodiless_responses() {
+        for status in [204, 304] {
+            let raw = format!("HTTP/1.1 {} X\r\nContent-Length: 5\r\n\r\n", status);
+            let (resp, len) = parse(raw.as_bytes(), Method::GET);
+            assert_eq!(resp.status.as_u16(), status);
+            assert!(resp.body.is_empty());
+            assert_eq!(len, raw.len());
+        }
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let (resp, len) = parse(raw, Method::HEAD);
+        assert!(resp.body.is_empty());
+        assert_eq!(len, raw.len());
+
+        let (_, len) = parse(raw, Method::CONNECT);
+        assert_eq!(len, raw.len());
+    }
+
+    #[test]
+    fn waits_for_partial_responses() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        for end in 0..raw.len() {
+            assert!(parse_response(&raw[..end], &Method::GET).unwrap().is_none());
+        }
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(parse_response(raw, &Method::GET).unwrap().is_none());
+    }
+
+    #[test]
+    fn notices_the_server_closing() {
+        let raw = b"HTTP/1.1 200 OK\r\nConnection: keep-alive, close\r\nContent-Length: 0\r\n\r\n";
+        assert!(parse(raw, Method::GET).0.close);
+    }
+
+    #[test]
+    fn requires_framed_bodies() {
+        let raw = b"HTTP/1.1 200 OK\r\n\r\nhello";
+        assert!(parse_response(raw, &Method::GET).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_back_to_back_responses() {
+        let raw: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok\
+            HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nnope\r\n0\r\n\r\n";
+
+        // Hand the responses over a byte at a time.
+        let (mut client, mut server) = tokio::io::duplex(1);
+        tokio::spawn(async move { server.write_all(raw).await });
+
+        let mut reader = ResponseReader::default();
+
+        let first = reader
+            .read_response(&mut client, &Method::GET)
+            .await
+            .unwrap();
+        assert_eq!(first.status, StatusCode::OK);
+        assert_eq!(first.body, b"ok");
+
+        let second = reader
+            .read_response(&mut client, &Method::GET)
+            .await
+            .unwrap();
+        assert_eq!(second.status, StatusCode::NOT_FOUND);
+        assert_eq!(second.body, b"nope");
+
+        let err = reader
+            .read_response(&mut client, &Method::GET)
+            .await
+            .unwrap_err();
+        let err = err.downcast::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn encodes_requests() {
+        let req = Request::post("/a?b=c")
+            .header("host", "example.com")
+            .body(Body::from("body"))
+            .unwrap();
+
+        let mut buf = Vec::new();
+        let body_len = encode_request(req, &mut buf).await.unwrap();
+        let encoded = String::from_utf8(buf).unwrap();
+
+        assert_eq!(body_len, 4);
+        assert!(encoded.starts_with("POST /a?b=c HTTP/1.1\r\nhost: example.com\r\n"));
+        assert!(encoded.ends_with("\r\n\r\nbody"));
+    }
+}
@@ -1,18 +1,30 @@
-use http::{request, HeaderMap};
+use http::request;
 use hyper::{Body, Request, Uri};
 
+use crate::error::AnyError;
+use crate::proto::template::RenderContext;
+use crate::proto::Target;
+
 pub trait HttpProtocol {
     fn is_http2(&self) -> bool;
 
-    fn request_builder(&self, uri: &Uri, headers: &HeaderMap) -> request::Builder;
+    fn request_builder(&self, target: &Target, ctx: &RenderContext) -> request::Builder;
+
+    fn get_request(&self, target: &Target, ctx: &RenderContext) -> Result<Request<Body>, AnyError> {
+        let req = self
+            .request_builder(target, ctx)
+            .body(target.render_body(ctx))?;
 
-    fn get_request(&self, uri: &Uri, headers: &HeaderMap) -> Request<Body> {
-        self.request_builder(uri, headers)
-            .body(Body::empty())
-            .expect("bad uri")
+        Ok(req)
     }
 
     fn alpn_protocols(&self) -> Vec<Vec<u8>>;
+
+    /// Decides if the connection speaks HTTP/2 given the protocol agreed
+    /// on with ALPN, if any.
+    fn use_http2(&self, _alpn: Option<&[u8]>) -> bool {
+        self.is_http2()
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -23,15 +35,17 @@ impl HttpProtocol for Http1 {
         false
     }
 
-    fn request_builder(&self, uri: &Uri, headers: &HeaderMap) -> request::Builder {
+    fn request_builder(&self, target: &Target, ctx: &RenderContext) -> request::Builder {
+        let uri = &target.uri;
         let host = host_header(uri);
 
-        let mut req = Request::builder().uri(uri.path());
+        let mut req = Request::builder().method(target.method.clone());
+        req = match target.render_path(ctx) {
+            Some(path) => req.uri(path),
+            None => req.uri(uri.path_and_query().map_or("/", |p| p.as_str())),
+        };
         req = req.header("Host", host);
-        for (k, v) in headers {
-            req = req.header(k, v);
-        }
-        req
+        target.apply_headers(req, ctx)
     }
 
     fn alpn_protocols(&self) -> Vec<Vec<u8>> {
@@ -47,14 +61,8 @@ impl HttpProtocol for Http2 {
         true
     }
 
-    fn request_builder(&self, uri: &Uri, headers: &HeaderMap) -> request::Builder {
-        let mut req = Request::builder().uri(uri);
-        // let host = host_header(uri);
-        // req = req.header(":authority", host);
-        for (k, v) in headers {
-            req = req.header(k, v);
-        }
-        req
+    fn request_builder(&self, target: &Target, ctx: &RenderContext) -> request::Builder {
+        absolute_request_builder(target, ctx)
     }
 
     fn alpn_protocols(&self) -> Vec<Vec<u8>> {
@@ -62,6 +70,69 @@ impl HttpProtocol for Http2 {
     }
 }
 
+/// Offers both HTTP/2 and HTTP/1.1 over TLS, using whichever the server
+/// picks. Cleartext connections use HTTP/1.1.
+#[derive(Clone, Copy)]
+pub struct HttpAuto;
+
+impl HttpProtocol for HttpAuto {
+    fn is_http2(&self) -> bool {
+        false
+    }
+
+    fn request_builder(&self, target: &Target, ctx: &RenderContext) -> request::Builder {
+        // HTTP/1 connections rewrite the request to origin form once the
+        // protocol is known.
+        absolute_request_builder(target, ctx)
+    }
+
+    fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    }
+
+    fn use_http2(&self, alpn: Option<&[u8]>) -> bool {
+        alpn == Some(b"h2")
+    }
+}
+
+#[cfg(feature = "http3")]
+#[derive(Clone, Copy)]
+pub struct Http3;
+
+#[cfg(feature = "http3")]
+impl HttpProtocol for Http3 {
+    fn is_http2(&self) -> bool {
+        false
+    }
+
+    fn request_builder(&self, target: &Target, ctx: &RenderContext) -> request::Builder {
+        absolute_request_builder(target, ctx)
+    }
+
+    fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        vec![b"h3".to_vec()]
+    }
+}
+
+/// Builds a request with an absolute uri, the scheme and authority are
+/// sent as pseudo headers in HTTP/2 and HTTP/3.
+fn absolute_request_builder(target: &Target, ctx: &RenderContext) -> request::Builder {
+    let uri = &target.uri;
+
+    let mut req = Request::builder().method(target.method.clone());
+    req = match target.render_path(ctx) {
+        Some(path) => {
+            let scheme = uri.scheme_str().unwrap_or("http");
+            let authority = uri.authority().map_or("", |a| a.as_str());
+            req.uri(format!("{}://{}{}", scheme, authority, path))
+        }
+        None => req.uri(uri),
+    };
+    // let host = host_header(uri);
+    // req = req.header(":authority", host);
+    target.apply_headers(req, ctx)
+}
+
 fn host_header(uri: &Uri) -> String {
     let invalid_uri = "Invalid URI";
 
@@ -0,0 +1,345 @@
+use crate::error::AnyError;
+use crate::proto::connector::{
+    CloseReason, Connect, Connection, RawConnection, ResponseFuture, Route, Sender,
+};
+use crate::proto::protocol::HttpProtocol;
+use crate::proto::tcp_stream::ByteCounter;
+use crate::proto::tls::{self, TlsOptions};
+use crate::proto::uri::Address;
+use crate::utils::BoxedFuture;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use futures_util::stream;
+
+use hyper::body::{Buf, Bytes};
+use hyper::{Body, Request, Response};
+
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Endpoint};
+
+/// Connects over QUIC, each connection gets its own endpoint and so its
+/// own UDP socket like a TCP connection would.
+pub struct QuicConnector {
+    domain: String,
+    config: ClientConfig,
+}
+
+impl QuicConnector {
+    pub fn new(domain: &str, alpn: &[Vec<u8>], tls: &TlsOptions) -> Result<Self, AnyError> {
+        let tls_config = tls::config_from_alpn(alpn, tls)?;
+        let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config)?));
+
+        Ok(Self {
+            domain: tls.server_name(domain).to_string(),
+            config,
+        })
+    }
+
+    async fn connect_quic(
+        &self,
+        addr: SocketAddr,
+        local: Option<IpAddr>,
+        counter: Arc<ByteCounter>,
+    ) -> Result<Connection, AnyError> {
+        let bind: SocketAddr = match (addr, local) {
+            (_, Some(local)) => (local, 0).into(),
+            (SocketAddr::V4(_), None) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            (SocketAddr::V6(_), None) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(self.config.clone());
+
+        let conn = endpoint.connect(addr, &self.domain)?.await?;
+
+        let (mut driver, send_request) =
+            h3::client::new(h3_quinn::Connection::new(conn.clone())).await?;
+
+        let handle = tokio::spawn(async move {
+            let closed = driver.wait_idle().await;
+
+            // The endpoint must outlive the connection.
+            drop(endpoint);
+
+            if closed.is_h3_no_error() {
+                CloseReason::ServerClosed
+            } else {
+                CloseReason::Error
+            }
+        });
+
+        let sender = Http3Sender {
+            send_request,
+            traffic: Arc::new(Traffic {
+                conn,
+                counter,
+                received: AtomicUsize::new(0),
+                sent: AtomicUsize::new(0),
+            }),
+        };
+
+        Ok(Connection {
+            send_request: Sender::Http3(sender),
+            handle,
+            // QUIC doesn't expose if the session was resumed.
+            tls: None,
+        })
+    }
+}
+
+impl Connect for QuicConnector {
+    fn handshake<S, P>(
+        &self,
+        _stream: S,
+        _protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        P: HttpProtocol + Send + Sync + 'static,
+    {
+        Box::pin(async { Err("QUIC connections are not made over a stream".into()) })
+    }
+
+    fn handshake_raw<S>(&self, _stream: S) -> BoxedFuture<'_, Result<RawConnection, AnyError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Box::pin(async { Err("QUIC connections are not made over a stream".into()) })
+    }
+
+    fn connect<P>(
+        &self,
+        addr: Address,
+        route: Route,
+        counter: Arc<ByteCounter>,
+        _protocol: P,
+    ) -> BoxedFuture<'_, Result<Connection, AnyError>>
+    where
+        Self: Sync,
+        P: HttpProtocol + Send + Sync + 'static,
+    {
+        Box::pin(async move {
+            match addr {
+                Address::Tcp(addr) => self.connect_quic(addr, route.local, counter).await,
+                Address::Unix(_) => Err("HTTP/3 can't connect over a unix socket".into()),
+            }
+        })
+    }
+}
+
+/// Sends requests over a HTTP/3 connection.
+pub struct Http3Sender {
+    send_request: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+    traffic: Arc<Traffic>,
+}
+
+impl Http3Sender {
+    /// Returns a future opening a new stream and sending the request,
+    /// resolving to the response once its head arrives. The body is
+    /// streamed like hyper's so the latency covers the same thing on
+    /// every protocol.
+    pub async fn dispatch(&mut self, req: Request<Body>) -> Result<ResponseFuture, AnyError> {
+        let (parts, body) = req.into_parts();
+
+        // The h3 crates use the 1.x release of http.
+        let mut builder = http_h3::Request::builder()
+            .method(parts.method.as_str())
+            .uri(parts.uri.to_string());
+        for (name, value) in parts.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        let head = builder.body(())?;
+
+        let mut send_request = self.send_request.clone();
+        let traffic = self.traffic.clone();
+
+        Ok(Box::pin(async move {
+            let mut stream = send_request.send_request(head).await?;
+
+            let body = hyper::body::to_bytes(body).await?;
+            if !body.is_empty() {
+                stream.send_data(body).await?;
+            }
+            stream.finish().await?;
+
+            let resp = stream.recv_response().await?;
+
+            let mut builder = Response::builder().status(resp.status().as_u16());
+            for (name, value) in resp.headers().iter() {
+                builder = builder.header(name.as_str(), value.as_bytes());
+            }
+
+            let body = stream::unfold(Some(stream), move |stream| {
+                let traffic = traffic.clone();
+
+                async move {
+                    let mut stream = stream?;
+                    match stream.recv_data().await {
+                        Ok(Some(mut chunk)) => {
+                            let chunk = chunk.copy_to_bytes(chunk.remaining());
+                            Some((Ok(chunk), Some(stream)))
+                        }
+                        Ok(None) => {
+                            traffic.record();
+                            None
+                        }
+                        Err(e) => Some((Err(e), None)),
+                    }
+                }
+            });
+
+            Ok(builder.body(Body::wrap_stream(body))?)
+        }))
+    }
+}
+
+/// The data sent and received on a QUIC connection.
+struct Traffic {
+    conn: quinn::Connection,
+
+    /// The amount of data read and written across all connections.
+    counter: Arc<ByteCounter>,
+
+    /// The amount of data read on this connection already added to the
+    /// counter.
+    received: AtomicUsize,
+
+    /// The amount of data written on this connection already added to the
+    /// counter.
+    sent: AtomicUsize,
+}
+
+impl Traffic {
+    /// Adds the data read and written since the last call to the counter.
+    ///
+    /// QUIC doesn't go through a counting stream, so the data read and
+    /// written is taken from the connection's own stats instead.
+    fn record(&self) {
+        let stats = self.conn.stats();
+
+        let total = stats.udp_rx.bytes as usize;
+        let previous = self.received.fetch_max(total, Ordering::AcqRel);
+        self.counter
+            .read
+            .fetch_add(total.saturating_sub(previous), Ordering::Release);
+
+        let total = stats.udp_tx.bytes as usize;
+        let previous = self.sent.fetch_max(total, Ordering::AcqRel);
+        self.counter
+            .written
+            .fetch_add(total.saturating_sub(previous), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    use quinn::crypto::rustls::QuicServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+
+    /// Starts a HTTP/3 server answering a single request by echoing its
+    /// body, which is held back until `release` fires.
+    fn echo_server(release: oneshot::Receiver<()>) -> SocketAddr {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let chain = vec![CertificateDer::from(cert.cert.der().to_vec())];
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key.into())
+            .unwrap();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+        let endpoint = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let conn = endpoint.accept().await.unwrap().await.unwrap();
+            let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+                .await
+                .unwrap();
+
+            let resolver = conn.accept().await.unwrap().unwrap();
+            let (req, mut stream) = resolver.resolve_request().await.unwrap();
+
+            let mut body = Vec::new();
+            while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+                body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+            }
+
+            let resp = http_h3::Response::builder()
+                .header("x-path", req.uri().path())
+                .body(())
+                .unwrap();
+            stream.send_response(resp).await.unwrap();
+
+            release.await.unwrap();
+            stream.send_data(Bytes::from(body)).await.unwrap();
+            stream.finish().await.unwrap();
+
+            // Keeps the connection open until the client is done.
+            let _ = conn.accept().await;
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn resolves_on_the_response_head() {
+        let (release, released) = oneshot::channel();
+        let addr = echo_server(released);
+
+        let options = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        let connector = QuicConnector::new("localhost", &[b"h3".to_vec()], &options).unwrap();
+        let counter = Arc::new(ByteCounter::default());
+
+        let conn = connector
+            .connect_quic(addr, None, counter.clone())
+            .await
+            .unwrap();
+        let mut sender = match conn.send_request {
+            Sender::Http3(sender) => sender,
+            _ => panic!("expected a HTTP/3 connection"),
+        };
+
+        let req = Request::post("https://localhost/echo")
+            .body(Body::from("hello"))
+            .unwrap();
+        let resp = sender.dispatch(req).await.unwrap();
+
+        // The server holds the body back, so this only resolves if the
+        // response doesn't wait for it.
+        let resp = timeout(Duration::from_secs(5), resp)
+            .await
+            .expect("the response waited for the body")
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["x-path"], "/echo");
+
+        release.send(()).unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+
+        assert!(counter.read.load(Ordering::Acquire) > 0);
+        assert!(counter.written.load(Ordering::Acquire) > 0);
+    }
+}
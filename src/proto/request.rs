@@ -0,0 +1,347 @@
+use crate::error::AnyError;
+
+use std::str::FromStr;
+
+use http::header::{HeaderName, HeaderValue};
+use http::{HeaderMap, Method};
+use hyper::body::Bytes;
+use serde_json::Value;
+
+/// Headers which are set by the client itself and must not be copied
+/// from a captured request.
+const SKIPPED_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "connection",
+    "transfer-encoding",
+    "keep-alive",
+    "upgrade",
+];
+
+/// A request to send during the benchmark.
+///
+/// The url may either be absolute or a path relative to the benchmarked
+/// host.
+#[derive(Clone, Debug)]
+pub struct RequestSpec {
+    pub url: String,
+    pub weight: u32,
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl RequestSpec {
+    /// A plain `GET` request to the given url.
+    pub fn get(url: String, weight: u32) -> Self {
+        Self {
+            url,
+            weight,
+            method: Method::GET,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    /// Resolves a relative url against the given base url, absolute
+    /// urls are left untouched.
+    pub fn resolve(mut self, base: Option<&str>) -> Result<Self, AnyError> {
+        if self.url.starts_with('/') {
+            let base = base.ok_or_else(|| format!("no host given for path {}", self.url))?;
+            self.url = format!("{}{}", base.trim_end_matches('/'), self.url);
+        }
+
+        Ok(self)
+    }
+}
+
+/// The order in which the requests are sent.
+#[derive(Clone, Copy, Debug)]
+pub enum ReplayOrder {
+    /// Requests are picked at random according to their weights.
+    Random,
+
+    /// Requests are sent one after the other, shared across all
+    /// connections.
+    RoundRobin,
+}
+
+impl FromStr for ReplayOrder {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "round-robin" => Ok(Self::RoundRobin),
+            other => Err(format!("unknown replay order: {}", other).into()),
+        }
+    }
+}
+
+/// Loads the requests from either a HAR capture or a file containing one
+/// json request per line.
+///
+/// Each line of a JSONL file looks like:
+/// `{"method": "POST", "path": "/users", "headers": {"a": "b"}, "body": "{}"}`
+/// where everything but the path (or `url`) is optional.
+pub fn load_requests(path: &str) -> Result<Vec<RequestSpec>, AnyError> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let requests = match serde_json::from_str::<Value>(&contents) {
+        Ok(value) if value.get("log").is_some() => parse_har(&value)?,
+        _ => parse_jsonl(&contents)?,
+    };
+
+    if requests.is_empty() {
+        return Err(format!("no requests found in {}", path).into());
+    }
+
+    Ok(requests)
+}
+
+fn parse_jsonl(contents: &str) -> Result<Vec<RequestSpec>, AnyError> {
+    let mut requests = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let value: Value =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+
+        let request = parse_jsonl_request(&value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        requests.push(request);
+    }
+
+    Ok(requests)
+}
+
+fn parse_jsonl_request(value: &Value) -> Result<RequestSpec, AnyError> {
+    let url = value
+        .get("url")
+        .or_else(|| value.get("path"))
+        .and_then(Value::as_str)
+        .ok_or("missing 'path' or 'url'")?;
+
+    let weight = match value.get("weight") {
+        Some(weight) => parse_weight(weight)?,
+        None => 1,
+    };
+
+    let mut request = RequestSpec::get(url.to_string(), weight);
+
+    if let Some(method) = value.get("method").and_then(Value::as_str) {
+        request.method = Method::from_str(method)?;
+    }
+
+    match value.get("headers") {
+        Some(Value::Object(headers)) => {
+            for (name, value) in headers {
+                let value = value.as_str().ok_or("header values must be strings")?;
+                add_header(&mut request.headers, name, value)?;
+            }
+        }
+        Some(Value::Array(headers)) => {
+            for header in headers {
+                let (name, value) = header_pair(header)?;
+                add_header(&mut request.headers, name, value)?;
+            }
+        }
+        Some(_) => return Err("invalid 'headers'".into()),
+        None => {}
+    }
+
+    if let Some(body) = value.get("body") {
+        request.body = match body {
+            Value::String(body) => Bytes::from(body.clone()),
+            other => Bytes::from(other.to_string()),
+        };
+    }
+
+    Ok(request)
+}
+
+/// Reads a request's weight, which must be a whole number of at least 1
+/// that fits in a `u32`.
+fn parse_weight(weight: &Value) -> Result<u32, AnyError> {
+    match weight.as_u64().map(u32::try_from) {
+        Some(Ok(0)) => Err("'weight' must be at least 1".into()),
+        Some(Ok(weight)) => Ok(weight),
+        _ => Err(format!("invalid 'weight': {}", weight).into()),
+    }
+}
+
+fn parse_har(value: &Value) -> Result<Vec<RequestSpec>, AnyError> {
+    let entries = value
+        .pointer("/log/entries")
+        .and_then(Value::as_array)
+        .ok_or("invalid HAR file, missing 'log.entries'")?;
+
+    let mut requests = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let request = entry.get("request").ok_or("HAR entry missing 'request'")?;
+
+        let url = request
+            .get("url")
+            .and_then(Value::as_str)
+            .ok_or("HAR request missing 'url'")?;
+
+        let mut spec = RequestSpec::get(url.to_string(), 1);
+
+        if let Some(method) = request.get("method").and_then(Value::as_str) {
+            spec.method = Method::from_str(method)?;
+        }
+
+        if let Some(headers) = request.get("headers").and_then(Value::as_array) {
+            for header in headers {
+                let (name, value) = header_pair(header)?;
+                add_header(&mut spec.headers, name, value)?;
+            }
+        }
+
+        if let Some(text) = request.pointer("/postData/text").and_then(Value::as_str) {
+            spec.body = Bytes::from(text.to_string());
+        }
+
+        requests.push(spec);
+    }
+
+    Ok(requests)
+}
+
+/// Extracts a header from either a `["name", "value"]` pair or a
+/// `{"name": .., "value": ..}` object.
+fn header_pair(header: &Value) -> Result<(&str, &str), AnyError> {
+    let pair = match header {
+        Value::Array(pair) if pair.len() == 2 => (pair[0].as_str(), pair[1].as_str()),
+        Value::Object(_) => (
+            header.get("name").and_then(Value::as_str),
+            header.get("value").and_then(Value::as_str),
+        ),
+        _ => (None, None),
+    };
+
+    match pair {
+        (Some(name), Some(value)) => Ok((name, value)),
+        _ => Err(format!("invalid header: {}", header).into()),
+    }
+}
+
+fn add_header(headers: &mut HeaderMap, name: &str, value: &str) -> Result<(), AnyError> {
+    // HTTP/2 pseudo headers and connection specific headers are
+    // handled by the client.
+    if name.starts_with(':') || SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        return Ok(());
+    }
+
+    headers.append(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_jsonl_requests() {
+        let contents = r#"
+{"path": "/a"}
+
+{"method": "POST", "url": "http://h/b", "weight": 3, "headers": {"x-a": "1"}, "body": "hi"}
+{"path": "/c", "headers": [["x-b", "2"], {"name": "x-c", "value": "3"}], "body": {"k": 1}}
+"#;
+
+        let requests = parse_jsonl(contents).unwrap();
+        assert_eq!(requests.len(), 3);
+
+        assert_eq!(requests[0].url, "/a");
+        assert_eq!(requests[0].method, Method::GET);
+        assert_eq!(requests[0].weight, 1);
+
+        assert_eq!(requests[1].url, "http://h/b");
+        assert_eq!(requests[1].method, Method::POST);
+        assert_eq!(requests[1].weight, 3);
+        assert_eq!(requests[1].headers["x-a"], "1");
+        assert_eq!(requests[1].body, "hi");
+
+        assert_eq!(requests[2].headers["x-b"], "2");
+        assert_eq!(requests[2].headers["x-c"], "3");
+        assert_eq!(requests[2].body, r#"{"k":1}"#);
+    }
+
+    #[test]
+    fn skips_client_managed_headers() {
+        let contents = r#"{"path": "/", "headers": {"Host": "h", ":path": "/", "x-a": "1"}}"#;
+
+        let requests = parse_jsonl(contents).unwrap();
+        assert_eq!(requests[0].headers.len(), 1);
+        assert!(requests[0].headers.contains_key("x-a"));
+    }
+
+    #[test]
+    fn rejects_invalid_weights_naming_the_line() {
+        for weight in ["0", "4294967296", "-1", "1.5", "\"2\""] {
+            let contents = format!(
+                "{{\"path\": \"/a\"}}\n{{\"path\": \"/b\", \"weight\": {}}}",
+                weight
+            );
+
+            let err = parse_jsonl(&contents).unwrap_err().to_string();
+            assert!(err.starts_with("line 2: "), "{}", err);
+            assert!(err.contains("'weight'"), "{}", err);
+        }
+
+        let contents = r#"{"path": "/a", "weight": 4294967295}"#;
+        assert_eq!(parse_jsonl(contents).unwrap()[0].weight, u32::MAX);
+    }
+
+    #[test]
+    fn rejects_requests_without_a_path() {
+        let err = parse_jsonl(r#"{"method": "GET"}"#).unwrap_err().to_string();
+        assert_eq!(err, "line 1: missing 'path' or 'url'");
+    }
+
+    #[test]
+    fn parses_har_entries() {
+        let har = serde_json::json!({
+            "log": {
+                "entries": [
+                    {"request": {"method": "GET", "url": "http://h/a", "headers": []}},
+                    {"request": {
+                        "method": "PUT",
+                        "url": "http://h/b",
+                        "headers": [{"name": "x-a", "value": "1"}],
+                        "postData": {"text": "body"},
+                    }},
+                ],
+            },
+        });
+
+        let requests = parse_har(&har).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].url, "http://h/a");
+        assert_eq!(requests[1].method, Method::PUT);
+        assert_eq!(requests[1].headers["x-a"], "1");
+        assert_eq!(requests[1].body, "body");
+    }
+
+    #[test]
+    fn resolves_relative_urls() {
+        let request = RequestSpec::get("/a".into(), 1);
+        assert_eq!(
+            request.resolve(Some("http://h/")).unwrap().url,
+            "http://h/a"
+        );
+
+        let request = RequestSpec::get("http://other/a".into(), 1);
+        assert_eq!(
+            request.resolve(Some("http://h")).unwrap().url,
+            "http://other/a"
+        );
+
+        assert!(RequestSpec::get("/a".into(), 1).resolve(None).is_err());
+    }
+}
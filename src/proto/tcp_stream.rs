@@ -1,20 +1,31 @@
 use std::pin::Pin;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::ReadBuf;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 
 use std::io::Result;
 
-pub struct CustomTcpStream {
-    inner: TcpStream,
-    counter: Arc<AtomicUsize>,
+/// The amount of data read and written on the wire, including headers and
+/// any TLS framing.
+#[derive(Debug, Default)]
+pub struct ByteCounter {
+    pub read: AtomicUsize,
+    pub written: AtomicUsize,
 }
 
-impl CustomTcpStream {
-    pub fn new(stream: TcpStream, counter: Arc<AtomicUsize>) -> Self {
+/// Wraps a stream, adding the amount of data read and written to the
+/// counter.
+pub struct CustomTcpStream<S = TcpStream> {
+    inner: S,
+    counter: Arc<ByteCounter>,
+}
+
+impl<S> CustomTcpStream<S> {
+    pub fn new(stream: S, counter: Arc<ByteCounter>) -> Self {
         Self {
             inner: stream,
             counter,
@@ -22,22 +33,147 @@ impl CustomTcpStream {
     }
 }
 
-impl AsyncRead for CustomTcpStream {
+impl<S: AsyncRead + Unpin> AsyncRead for CustomTcpStream<S> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<()>> {
+        let filled = buf.filled().len();
         let result = Pin::new(&mut self.inner).poll_read(cx, buf);
 
         self.counter
-            .fetch_add(buf.filled().len(), Ordering::Release);
+            .read
+            .fetch_add(buf.filled().len() - filled, Ordering::Release);
 
         result
     }
 }
 
-impl AsyncWrite for CustomTcpStream {
+impl<S: AsyncWrite + Unpin> AsyncWrite for CustomTcpStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = result {
+            self.counter.written.fetch_add(n, Ordering::Release);
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// The length of a HTTP/2 frame header.
+const FRAME_HEADER_LEN: usize = 9;
+
+/// The type of a HTTP/2 `HEADERS` frame.
+const HEADERS_FRAME: u8 = 0x1;
+
+/// The type of a HTTP/2 `GOAWAY` frame.
+const GO_AWAY_FRAME: u8 = 0x7;
+
+/// Wraps the stream of a HTTP/2 connection, watching the frames read for
+/// a `GOAWAY` so a server shutting the connection down gracefully can be
+/// told apart from one just closing it, and for the response to a h2c
+/// upgrade.
+pub struct FrameWatch<S> {
+    inner: S,
+    go_away: Arc<AtomicBool>,
+
+    /// Fired once the headers of the response to a h2c upgrade request,
+    /// which always takes the first stream, are read.
+    upgrade_response: Option<oneshot::Sender<()>>,
+
+    /// The header of the frame being read.
+    header: [u8; FRAME_HEADER_LEN],
+    header_filled: usize,
+
+    /// The amount of the frame's payload still to be read.
+    payload_left: usize,
+}
+
+impl<S> FrameWatch<S> {
+    pub fn new(stream: S, go_away: Arc<AtomicBool>) -> Self {
+        Self {
+            inner: stream,
+            go_away,
+            upgrade_response: None,
+            header: [0; FRAME_HEADER_LEN],
+            header_filled: 0,
+            payload_left: 0,
+        }
+    }
+
+    /// Tells the given channel when the response to the upgrade request
+    /// of a h2c connection starts arriving.
+    pub fn with_upgrade_response(mut self, tx: oneshot::Sender<()>) -> Self {
+        self.upgrade_response = Some(tx);
+        self
+    }
+
+    /// Follows the frames through the data read, which can split them up
+    /// anywhere.
+    fn watch(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.payload_left > 0 {
+                let skipped = self.payload_left.min(data.len());
+                self.payload_left -= skipped;
+                data = &data[skipped..];
+                continue;
+            }
+
+            let copied = (FRAME_HEADER_LEN - self.header_filled).min(data.len());
+            self.header[self.header_filled..self.header_filled + copied]
+                .copy_from_slice(&data[..copied]);
+            self.header_filled += copied;
+            data = &data[copied..];
+
+            if self.header_filled == FRAME_HEADER_LEN {
+                let [a, b, c, kind, _, id @ ..] = self.header;
+                let stream_id = u32::from_be_bytes(id) & 0x7fff_ffff;
+
+                if kind == GO_AWAY_FRAME {
+                    self.go_away.store(true, Ordering::Release);
+                } else if kind == HEADERS_FRAME && stream_id == 1 {
+                    if let Some(tx) = self.upgrade_response.take() {
+                        let _ = tx.send(());
+                    }
+                }
+
+                self.payload_left = u32::from_be_bytes([0, a, b, c]) as usize;
+                self.header_filled = 0;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FrameWatch<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        self.watch(&buf.filled()[filled..]);
+
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FrameWatch<S> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -54,3 +190,74 @@ impl AsyncWrite for CustomTcpStream {
         Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::client::conn;
+    use hyper::{Body, Request};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\ncontent-length: 11\r\n\r\nhello world";
+
+    #[tokio::test]
+    async fn counts_the_bytes_on_the_wire() {
+        let (client, mut server) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut req = Vec::new();
+            while !req.ends_with(b"\r\n\r\nupload") {
+                req.push(server.read_u8().await.unwrap());
+            }
+
+            server.write_all(RESPONSE).await.unwrap();
+            req.len()
+        });
+
+        let counter = Arc::new(ByteCounter::default());
+        let stream = CustomTcpStream::new(client, counter.clone());
+        let (mut send_request, connection) = conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let req = Request::post("/")
+            .header("host", "localhost")
+            .body(Body::from("upload"))
+            .unwrap();
+        let resp = send_request.send_request(req).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+
+        let written = server.await.unwrap();
+        assert_eq!(counter.written.load(Ordering::Acquire), written);
+        assert_eq!(counter.read.load(Ordering::Acquire), RESPONSE.len());
+    }
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, 0, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn watched(data: &[u8], split: usize) -> bool {
+        let go_away = Arc::new(AtomicBool::new(false));
+        let mut watch = FrameWatch::new((), go_away.clone());
+        for chunk in data.chunks(split) {
+            watch.watch(chunk);
+        }
+
+        go_away.load(Ordering::Acquire)
+    }
+
+    #[test]
+    fn spots_go_away_frames() {
+        let mut data = frame(0x4, &[0; 6]);
+        data.extend(frame(0x0, b"\x07\x07\x07\x07\x07\x07\x07\x07\x07"));
+        assert!(!watched(&data, data.len()));
+
+        data.extend(frame(GO_AWAY_FRAME, &[0; 8]));
+        for split in [1, 2, 5, 9, 10, data.len()] {
+            assert!(watched(&data, split), "split every {} bytes", split);
+        }
+    }
+}
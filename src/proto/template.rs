@@ -0,0 +1,251 @@
+use crate::error::AnyError;
+
+use std::fmt::Write;
+
+use rand::Rng;
+
+/// The per request values used to render a template.
+pub struct RenderContext<'a> {
+    /// The sequence number of the request.
+    pub seq: u64,
+
+    /// The id of the connection sending the request.
+    pub connection_id: usize,
+
+    /// The row of the data file used for this request.
+    pub row: Option<&'a [String]>,
+}
+
+/// A CSV file with a header row, whose values can be used in templates
+/// with `{{data.<column>}}`.
+#[derive(Debug)]
+pub struct DataFile {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl DataFile {
+    pub fn load(path: &str) -> Result<Self, AnyError> {
+        let mut reader = csv::Reader::from_path(path)?;
+
+        let columns = reader
+            .headers()?
+            .iter()
+            .map(|c| c.trim().to_string())
+            .collect();
+
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            rows.push(record?.iter().map(str::to_string).collect());
+        }
+
+        if rows.is_empty() {
+            return Err(format!("no rows found in {}", path).into());
+        }
+
+        Ok(Self { columns, rows })
+    }
+
+    /// Gets the row for the given request, cycling through the file.
+    pub fn row(&self, seq: u64) -> &[String] {
+        &self.rows[(seq % self.rows.len() as u64) as usize]
+    }
+
+    fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c == name)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Uuid,
+    RandInt(u64, u64),
+    Seq,
+    ConnectionId,
+    Data(usize),
+}
+
+/// A pre-compiled template, e.g. `/users/{{rand_int 1 100}}`.
+///
+/// Supported placeholders are `{{uuid}}`, `{{rand_int <min> <max>}}`,
+/// `{{seq}}`, `{{connection_id}}` and `{{data.<column>}}`.
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Returns if the given string contains any placeholders.
+    pub fn is_template(s: &str) -> bool {
+        s.contains("{{")
+    }
+
+    pub fn compile(s: &str, data: Option<&DataFile>) -> Result<Self, AnyError> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("unclosed placeholder in template: {}", s))?;
+
+            let placeholder = rest[start + 2..start + end].trim();
+            segments.push(compile_placeholder(placeholder, data)?);
+
+            rest = &rest[start + end + 2..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, ctx: &RenderContext) -> String {
+        let mut out = String::new();
+
+        for segment in self.segments.iter() {
+            let _ = match segment {
+                Segment::Literal(s) => {
+                    out.push_str(s);
+                    Ok(())
+                }
+                Segment::Uuid => write_uuid(&mut out),
+                Segment::RandInt(min, max) => {
+                    write!(out, "{}", rand::thread_rng().gen_range(*min..=*max))
+                }
+                Segment::Seq => write!(out, "{}", ctx.seq),
+                Segment::ConnectionId => write!(out, "{}", ctx.connection_id),
+                Segment::Data(column) => {
+                    if let Some(value) = ctx.row.and_then(|row| row.get(*column)) {
+                        out.push_str(value);
+                    }
+                    Ok(())
+                }
+            };
+        }
+
+        out
+    }
+}
+
+fn compile_placeholder(placeholder: &str, data: Option<&DataFile>) -> Result<Segment, AnyError> {
+    let mut parts = placeholder.split_whitespace();
+
+    let segment = match parts.next() {
+        Some("uuid") => Segment::Uuid,
+        Some("seq") => Segment::Seq,
+        Some("connection_id") => Segment::ConnectionId,
+        Some("rand_int") => {
+            let mut bound = || -> Result<u64, AnyError> {
+                let bound = parts.next().ok_or("rand_int requires a min and max")?;
+                Ok(bound.parse::<u64>()?)
+            };
+
+            let (min, max) = (bound()?, bound()?);
+            if min > max {
+                return Err(format!("invalid rand_int range: {} > {}", min, max).into());
+            }
+
+            Segment::RandInt(min, max)
+        }
+        Some(name) if name.starts_with("data.") => {
+            let column = &name["data.".len()..];
+            let data = data.ok_or("a data file is required to use data placeholders")?;
+            let column = data
+                .column(column)
+                .ok_or_else(|| format!("unknown data file column: {}", column))?;
+
+            Segment::Data(column)
+        }
+        _ => return Err(format!("unknown template placeholder: {{{{{}}}}}", placeholder).into()),
+    };
+
+    Ok(segment)
+}
+
+/// Writes a random (version 4) UUID.
+fn write_uuid(out: &mut String) -> std::fmt::Result {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    for (i, b) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            out.push('-');
+        }
+        write!(out, "{:02x}", b)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(row: Option<&[String]>) -> RenderContext<'_> {
+        RenderContext {
+            seq: 7,
+            connection_id: 3,
+            row,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = Template::compile("/a/{{seq}}/{{ connection_id }}?x=1", None).unwrap();
+        assert_eq!(template.render(&ctx(None)), "/a/7/3?x=1");
+    }
+
+    #[test]
+    fn renders_rand_int_within_its_range() {
+        let template = Template::compile("{{rand_int 5 6}}", None).unwrap();
+        for _ in 0..32 {
+            let value: u64 = template.render(&ctx(None)).parse().unwrap();
+            assert!((5..=6).contains(&value));
+        }
+    }
+
+    #[test]
+    fn renders_version_4_uuids() {
+        let uuid = Template::compile("{{uuid}}", None)
+            .unwrap()
+            .render(&ctx(None));
+
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+    }
+
+    #[test]
+    fn renders_data_columns() {
+        let data = DataFile {
+            columns: vec!["id".into(), "name".into()],
+            rows: vec![vec!["1".into(), "a".into()], vec!["2".into(), "b".into()]],
+        };
+
+        let template = Template::compile("{{data.name}}-{{data.id}}", Some(&data)).unwrap();
+        assert_eq!(template.render(&ctx(Some(data.row(0)))), "a-1");
+        assert_eq!(template.render(&ctx(Some(data.row(3)))), "b-2");
+    }
+
+    #[test]
+    fn rejects_invalid_placeholders() {
+        let data = DataFile {
+            columns: vec!["id".into()],
+            rows: vec![vec!["1".into()]],
+        };
+
+        assert!(Template::compile("{{seq", None).is_err());
+        assert!(Template::compile("{{nope}}", None).is_err());
+        assert!(Template::compile("{{rand_int 5}}", None).is_err());
+        assert!(Template::compile("{{rand_int 6 5}}", None).is_err());
+        assert!(Template::compile("{{data.id}}", None).is_err());
+        assert!(Template::compile("{{data.missing}}", Some(&data)).is_err());
+    }
+}
@@ -1,26 +1,662 @@
 use crate::error::AnyError;
 
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use rustls::{ClientConfig, RootCertStore};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::Resumption;
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::version::{TLS12, TLS13};
+use rustls::{
+    CipherSuite, ClientConfig, ClientConnection, DigitallySignedStruct, HandshakeKind,
+    ProtocolVersion, RootCertStore, SignatureScheme, SupportedProtocolVersion,
+};
 use rustls_native_certs::load_native_certs;
 use tokio_rustls::TlsConnector;
 
-pub fn connector_from_alpn(alpn: &[Vec<u8>]) -> Result<TlsConnector, AnyError> {
-    let mut root_cert_store = RootCertStore::empty();
-    let root_ca = load_native_certs().map_err(|_| "cant load native certificates")?;
-    for cert in root_ca {
-        root_cert_store.add(&rustls::Certificate(cert.0))?;
+/// How TLS connections verify the server.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM files of extra certificates to trust alongside the native
+    /// roots, e.g. an internal CA.
+    pub ca_certs: Vec<PathBuf>,
+
+    /// Accepts any server certificate without verifying it.
+    pub insecure: bool,
+
+    /// The server name sent with SNI and verified against the
+    /// certificate, defaulting to the url's host.
+    pub sni: Option<String>,
+
+    /// The PEM file of the client certificate chain presented for mutual
+    /// TLS, requires `key` to be set as well.
+    pub cert: Option<PathBuf>,
+
+    /// The PEM file of the client certificate's private key, in PKCS#8,
+    /// PKCS#1 (RSA) or SEC1 (EC) form.
+    pub key: Option<PathBuf>,
+
+    /// Always performs a full handshake instead of resuming sessions from
+    /// earlier connections.
+    pub disable_resumption: bool,
+
+    /// Pins the TLS version instead of offering both 1.2 and 1.3.
+    pub version: Option<TlsVersion>,
+
+    /// The names of the cipher suites to offer, e.g.
+    /// `TLS13_AES_128_GCM_SHA256`, offering all supported suites when
+    /// empty.
+    pub ciphers: Vec<String>,
+}
+
+/// A TLS version that can be pinned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            other => Err(format!("unknown tls version: {}", other).into()),
+        }
+    }
+}
+
+impl TlsOptions {
+    /// The server name to connect with, preferring the SNI override.
+    pub fn server_name<'a>(&'a self, host: &'a str) -> &'a str {
+        self.sni.as_deref().unwrap_or(host)
     }
+}
+
+/// How a connection's TLS handshake went.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsHandshake {
+    pub took: Duration,
+
+    /// If the session was resumed from an earlier connection.
+    pub resumed: bool,
+
+    pub version: Option<ProtocolVersion>,
+    pub suite: Option<CipherSuite>,
+}
+
+impl TlsHandshake {
+    pub fn new(took: Duration, conn: &ClientConnection) -> Self {
+        Self {
+            took,
+            resumed: conn.handshake_kind() == Some(HandshakeKind::Resumed),
+            version: conn.protocol_version(),
+            suite: conn.negotiated_cipher_suite().map(|suite| suite.suite()),
+        }
+    }
+
+    /// Describes the negotiated version and cipher suite, e.g.
+    /// `TLS 1.3 TLS13_AES_256_GCM_SHA384`.
+    pub fn session(&self) -> String {
+        let version = match self.version {
+            Some(ProtocolVersion::TLSv1_2) => "TLS 1.2",
+            Some(ProtocolVersion::TLSv1_3) => "TLS 1.3",
+            Some(other) => other.as_str().unwrap_or("unknown"),
+            None => "unknown",
+        };
+
+        let suite = self
+            .suite
+            .and_then(|suite| suite.as_str())
+            .unwrap_or("unknown");
+
+        format!("{} {}", version, suite)
+    }
+}
+
+/// Builds the client TLS config trusting the native certificates, shared
+/// by both the TCP and QUIC connectors.
+pub fn config_from_alpn(alpn: &[Vec<u8>], options: &TlsOptions) -> Result<ClientConfig, AnyError> {
+    let mut provider = ring::default_provider();
+    if !options.ciphers.is_empty() {
+        provider.cipher_suites = select_ciphers(&provider, &options.ciphers)?;
+    }
+
+    let versions: &[&SupportedProtocolVersion] = match options.version {
+        Some(TlsVersion::Tls12) => &[&TLS12],
+        Some(TlsVersion::Tls13) => &[&TLS13],
+        None => rustls::DEFAULT_VERSIONS,
+    };
+
+    let provider = Arc::new(provider);
+    let builder =
+        ClientConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+
+    let builder = if options.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+    } else {
+        builder.with_root_certificates(root_certs(options)?)
+    };
 
-    let mut config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+    let mut config = match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => {
+            let (certs, key) = client_auth(cert, key)?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("a client certificate needs both a cert and a key".into()),
+    };
 
     config.alpn_protocols = alpn.into();
 
+    if options.disable_resumption {
+        config.resumption = Resumption::disabled();
+    }
+
+    Ok(config)
+}
+
+pub fn connector_from_alpn(
+    alpn: &[Vec<u8>],
+    options: &TlsOptions,
+) -> Result<TlsConnector, AnyError> {
+    let config = config_from_alpn(alpn, options)?;
+
     let connector = Arc::new(config).into();
 
     Ok(connector)
 }
+
+/// Picks the provider's cipher suites matching the given names, ignoring
+/// case.
+fn select_ciphers(
+    provider: &CryptoProvider,
+    names: &[String],
+) -> Result<Vec<rustls::SupportedCipherSuite>, AnyError> {
+    let name_of = |suite: &rustls::SupportedCipherSuite| suite.suite().as_str().unwrap_or("");
+
+    names
+        .iter()
+        .map(|name| {
+            provider
+                .cipher_suites
+                .iter()
+                .find(|suite| name_of(suite).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| {
+                    let supported: Vec<&str> = provider.cipher_suites.iter().map(name_of).collect();
+                    format!(
+                        "unknown cipher suite {}, supported suites are: {}",
+                        name,
+                        supported.join(", ")
+                    )
+                    .into()
+                })
+        })
+        .collect()
+}
+
+/// Loads the native certificates along with any given CA files.
+fn root_certs(options: &TlsOptions) -> Result<RootCertStore, AnyError> {
+    let mut root_cert_store = RootCertStore::empty();
+    let root_ca = load_native_certs().map_err(|_| "cant load native certificates")?;
+    for cert in root_ca {
+        root_cert_store.add(CertificateDer::from(cert.0))?;
+    }
+
+    for path in options.ca_certs.iter() {
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("cant load CA certificates from {}: {}", path.display(), e))?;
+
+        if certs.is_empty() {
+            return Err(format!("no CA certificates found in {}", path.display()).into());
+        }
+
+        for cert in certs {
+            root_cert_store.add(cert)?;
+        }
+    }
+
+    Ok(root_cert_store)
+}
+
+/// Loads the client certificate chain and its private key.
+fn client_auth(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), AnyError> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!(
+                "cant load client certificate from {}: {}",
+                cert.display(),
+                e
+            )
+        })?;
+
+    if certs.is_empty() {
+        return Err(format!("no client certificate found in {}", cert.display()).into());
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("cant load private key from {}: {}", key.display(), e))?;
+
+    Ok((certs, key))
+}
+
+/// Accepts any server certificate, only checking the handshake is signed
+/// by it so the connection still completes normally.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::ServerConfig;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Writes a file for the test to the temp directory, named after the
+    /// process so parallel runs don't clash.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rewrk-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn staging_cert() -> rcgen::CertifiedKey {
+        rcgen::generate_simple_self_signed(vec!["staging.internal".into()]).unwrap()
+    }
+
+    /// Handshakes with a server presenting the given certificate,
+    /// returning the server name the server saw.
+    async fn handshake(
+        cert: &rcgen::CertifiedKey,
+        options: &TlsOptions,
+        host: &str,
+    ) -> Result<Option<String>, AnyError> {
+        let chain = vec![CertificateDer::from(cert.cert.der().to_vec())];
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key.into())
+            .unwrap();
+
+        let (client, server) = duplex(64 * 1024);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            if let Ok(mut stream) = TlsAcceptor::from(Arc::new(config)).accept(server).await {
+                let _ = tx.send(stream.get_ref().1.server_name().map(str::to_string));
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            }
+        });
+
+        let connector = connector_from_alpn(&[], options)?;
+        let name = ServerName::try_from(options.server_name(host).to_string())?;
+        let _stream = connector.connect(name, client).await?;
+
+        Ok(rx.await?)
+    }
+
+    #[tokio::test]
+    async fn trusts_the_given_ca_certificates() {
+        let cert = staging_cert();
+
+        let err = handshake(&cert, &TlsOptions::default(), "staging.internal")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("UnknownIssuer"), "{}", err);
+
+        let options = TlsOptions {
+            ca_certs: vec![temp_file("trusted-ca.pem", &cert.cert.pem())],
+            ..Default::default()
+        };
+        let sni = handshake(&cert, &options, "staging.internal")
+            .await
+            .unwrap();
+        assert_eq!(sni.as_deref(), Some("staging.internal"));
+
+        // The certificate still has to match the host.
+        assert!(handshake(&cert, &options, "other.internal").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn overrides_the_server_name() {
+        let cert = staging_cert();
+        let options = TlsOptions {
+            ca_certs: vec![temp_file("sni-ca.pem", &cert.cert.pem())],
+            sni: Some("staging.internal".into()),
+            ..Default::default()
+        };
+
+        let sni = handshake(&cert, &options, "127.0.0.1").await.unwrap();
+        assert_eq!(sni.as_deref(), Some("staging.internal"));
+    }
+
+    #[tokio::test]
+    async fn skips_verification_when_insecure() {
+        let options = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        let sni = handshake(&staging_cert(), &options, "other.internal").await;
+        assert_eq!(sni.unwrap().as_deref(), Some("other.internal"));
+    }
+
+    /// Issues a client certificate from a new CA, returning the CA's
+    /// certificate and the paths of the client's cert and key files.
+    fn client_cert(name: &str) -> (CertificateDer<'static>, PathBuf, PathBuf) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["client".into()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        let cert_path = temp_file(&format!("{}-cert.pem", name), &cert.pem());
+        let key_path = temp_file(&format!("{}-key.pem", name), &key.serialize_pem());
+        (ca.der().clone(), cert_path, key_path)
+    }
+
+    #[tokio::test]
+    async fn presents_the_client_certificate() {
+        let (ca, cert, key) = client_cert("mtls");
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            rustls::server::WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .build()
+                .unwrap();
+
+        let server_cert = staging_cert();
+        let chain = vec![CertificateDer::from(server_cert.cert.der().to_vec())];
+        let server_key = PrivatePkcs8KeyDer::from(server_cert.key_pair.serialize_der());
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain, server_key.into())
+            .unwrap();
+
+        let (client, server) = duplex(64 * 1024);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut stream = TlsAcceptor::from(Arc::new(config))
+                .accept(server)
+                .await
+                .unwrap();
+            let _ = tx.send(stream.get_ref().1.peer_certificates().map(<[_]>::len));
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let options = TlsOptions {
+            insecure: true,
+            cert: Some(cert),
+            key: Some(key),
+            ..Default::default()
+        };
+        let connector = connector_from_alpn(&[], &options).unwrap();
+        let name = ServerName::try_from("staging.internal").unwrap();
+        let _stream = connector.connect(name, client).await.unwrap();
+
+        assert_eq!(rx.await.unwrap(), Some(1));
+    }
+
+    #[test]
+    fn pairs_client_certificates_with_their_key() {
+        let (_, cert, key) = client_cert("paired");
+        let (_, _, other_key) = client_cert("unpaired");
+
+        let options = TlsOptions {
+            insecure: true,
+            cert: Some(cert.clone()),
+            key: Some(key),
+            ..Default::default()
+        };
+        let config = config_from_alpn(&[], &options).unwrap();
+        assert!(config.client_auth_cert_resolver.has_certs());
+
+        let mismatched = TlsOptions {
+            key: Some(other_key),
+            ..options.clone()
+        };
+        let err = config_from_alpn(&[], &mismatched).unwrap_err();
+        assert!(err.to_string().contains("KeyMismatch"), "{}", err);
+
+        let swapped = TlsOptions {
+            cert: options.key.clone(),
+            key: Some(cert),
+            ..options.clone()
+        };
+        let err = config_from_alpn(&[], &swapped).unwrap_err().to_string();
+        assert!(err.starts_with("no client certificate found in"), "{}", err);
+
+        let missing = TlsOptions {
+            key: Some("/nonexistent/key.pem".into()),
+            ..options
+        };
+        let err = config_from_alpn(&[], &missing).unwrap_err().to_string();
+        assert!(err.starts_with("cant load private key from /nonexistent/key.pem"));
+    }
+
+    /// Connects to the server twice with the same connector, returning if
+    /// each of the handshakes resumed a session.
+    async fn resumed(options: &TlsOptions) -> Vec<bool> {
+        let cert = staging_cert();
+        let chain = vec![CertificateDer::from(cert.cert.der().to_vec())];
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key.into())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let connector = connector_from_alpn(&[], options).unwrap();
+
+        let mut resumed = Vec::new();
+        for _ in 0..2 {
+            let (client, server) = duplex(64 * 1024);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = acceptor.accept(server).await.unwrap();
+                stream.write_all(b"ok").await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            let name = ServerName::try_from("staging.internal").unwrap();
+            let mut stream = connector.connect(name, client).await.unwrap();
+
+            // Reading takes in the session tickets sent after the handshake.
+            let mut ok = [0; 2];
+            stream.read_exact(&mut ok).await.unwrap();
+
+            let handshake = TlsHandshake::new(Duration::ZERO, stream.get_ref().1);
+            resumed.push(handshake.resumed);
+        }
+
+        resumed
+    }
+
+    #[tokio::test]
+    async fn reports_resumed_sessions() {
+        let options = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        assert_eq!(resumed(&options).await, [false, true]);
+
+        let options = TlsOptions {
+            disable_resumption: true,
+            ..options
+        };
+        assert_eq!(resumed(&options).await, [false, false]);
+    }
+
+    #[test]
+    fn rejects_bad_ca_files() {
+        let options = TlsOptions {
+            ca_certs: vec![temp_file("empty-ca.pem", "")],
+            ..Default::default()
+        };
+        let err = config_from_alpn(&[], &options).unwrap_err().to_string();
+        assert!(err.starts_with("no CA certificates found in"), "{}", err);
+
+        let options = TlsOptions {
+            ca_certs: vec!["/nonexistent/ca.pem".into()],
+            ..Default::default()
+        };
+        let err = config_from_alpn(&[], &options).unwrap_err().to_string();
+        assert!(err.starts_with("cant load CA certificates from /nonexistent/ca.pem"));
+    }
+
+    #[test]
+    fn parses_tls_versions() {
+        assert_eq!("1.2".parse::<TlsVersion>().unwrap(), TlsVersion::Tls12);
+        assert_eq!("1.3".parse::<TlsVersion>().unwrap(), TlsVersion::Tls13);
+        assert!("1.1".parse::<TlsVersion>().is_err());
+        assert!("tls1.3".parse::<TlsVersion>().is_err());
+    }
+
+    #[test]
+    fn selects_ciphers_by_name() {
+        let provider = ring::default_provider();
+        let names = [
+            "tls13_chacha20_poly1305_sha256".to_string(),
+            "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+        ];
+
+        let suites = select_ciphers(&provider, &names).unwrap();
+        let selected: Vec<_> = suites.iter().map(|suite| suite.suite()).collect();
+        assert_eq!(
+            selected,
+            [
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_ciphers() {
+        let provider = ring::default_provider();
+        let err = select_ciphers(&provider, &["TLS_RSA_WITH_RC4_128_MD5".to_string()])
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.starts_with("unknown cipher suite TLS_RSA_WITH_RC4_128_MD5"));
+        assert!(err.contains("TLS13_AES_128_GCM_SHA256"));
+    }
+
+    #[test]
+    fn describes_sessions() {
+        let handshake = TlsHandshake {
+            took: Duration::ZERO,
+            resumed: false,
+            version: Some(ProtocolVersion::TLSv1_3),
+            suite: Some(CipherSuite::TLS13_AES_256_GCM_SHA384),
+        };
+        assert_eq!(handshake.session(), "TLS 1.3 TLS13_AES_256_GCM_SHA384");
+
+        let unknown = TlsHandshake {
+            version: None,
+            suite: None,
+            ..handshake
+        };
+        assert_eq!(unknown.session(), "unknown unknown");
+    }
+
+    #[test]
+    fn pins_versions_and_ciphers() {
+        let options = TlsOptions {
+            insecure: true,
+            version: Some(TlsVersion::Tls13),
+            ciphers: vec!["TLS13_AES_128_GCM_SHA256".to_string()],
+            ..Default::default()
+        };
+        let config = config_from_alpn(&[b"h2".to_vec()], &options).unwrap();
+
+        assert_eq!(config.alpn_protocols, [b"h2".to_vec()]);
+        assert_eq!(
+            config
+                .crypto_provider()
+                .cipher_suites
+                .iter()
+                .map(|suite| suite.suite())
+                .collect::<Vec<_>>(),
+            [CipherSuite::TLS13_AES_128_GCM_SHA256]
+        );
+
+        let options = TlsOptions {
+            cert: Some("client.pem".into()),
+            ..options
+        };
+        assert!(config_from_alpn(&[], &options).is_err());
+    }
+}
@@ -1,12 +1,26 @@
 use crate::error::AnyError;
+use crate::proto::proxy::Proxy;
+use crate::proto::request::{ReplayOrder, RequestSpec};
+use crate::proto::template::{DataFile, RenderContext, Template};
+use crate::script::ScriptedRequest;
 
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use hyper::Uri;
+use http::header::{HeaderName, HeaderValue};
+use http::{request, HeaderMap, Method};
+use hyper::body::Bytes;
+use hyper::{Body, Uri};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Scheme {
     HTTP,
     HTTPS,
@@ -39,45 +53,775 @@ impl From<Option<&str>> for Scheme {
     }
 }
 
-pub struct ParsedUri {
+/// Where the connections of a group of targets are made to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+
+    /// A Unix domain socket, the url's host is still sent in the `Host`
+    /// header.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How connections pick between the addresses a host resolves to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AddrStrategy {
+    /// Every connection uses the first IPv4 address, falling back to the
+    /// first address.
+    #[default]
+    First,
+
+    /// Connections take turns using each of the addresses.
+    RoundRobin,
+
+    /// Each connection uses an address picked at random.
+    Random,
+
+    /// Every connection uses the first IPv6 address, falling back to the
+    /// first address.
+    Ipv6Preferred,
+}
+
+impl FromStr for AddrStrategy {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Self::First),
+            "round-robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            "ipv6-preferred" => Ok(Self::Ipv6Preferred),
+            other => Err(format!("unknown address strategy: {}", other).into()),
+        }
+    }
+}
+
+/// Connects to fixed addresses for a host and port instead of looking the
+/// host up, like curl's `--resolve host:port:addr[,addr...]`.
+///
+/// The `Host` header and TLS server name still come from the url.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolve {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Resolve {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.port == port && self.host.eq_ignore_ascii_case(trim_brackets(host))
+    }
+}
+
+impl FromStr for Resolve {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected host:port:addr[,addr...] but got '{}'", s);
+
+        let (host, rest) = match s.strip_prefix('[') {
+            Some(s) => s.split_once("]:").ok_or_else(invalid)?,
+            None => s.split_once(':').ok_or_else(invalid)?,
+        };
+        let (port, addrs) = rest.split_once(':').ok_or_else(invalid)?;
+
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port '{}' in '{}'", port, s))?;
+
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                trim_brackets(addr)
+                    .parse()
+                    .map_err(|_| format!("invalid address '{}' in '{}'", addr, s))
+            })
+            .collect::<Result<Vec<IpAddr>, _>>()?;
+
+        if host.is_empty() {
+            return Err(invalid().into());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            addrs,
+        })
+    }
+}
+
+fn trim_brackets(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// How the addresses of each group are looked up.
+#[derive(Clone, Debug, Default)]
+pub struct LookupOptions {
+    /// Connects to this Unix socket instead of looking the host up.
+    pub unix_socket: Option<PathBuf>,
+
+    pub strategy: AddrStrategy,
+
+    /// Looks the host up again once this long has passed since the last
+    /// lookup, never refreshing when unset.
+    pub refresh: Option<Duration>,
+
+    /// Fixed addresses used instead of looking up matching hosts.
+    pub resolve: Vec<Resolve>,
+
+    /// Looks the proxy up instead of the hosts, which are resolved when
+    /// the tunnel is opened, by the proxy itself unless it's `socks5`.
+    pub proxy: Option<Proxy>,
+}
+
+/// A single request which can be sent and its relative weight.
+#[derive(Clone)]
+pub struct Target {
     pub uri: Uri,
+    pub weight: u32,
+    pub method: Method,
+
+    /// The headers sent with the request, including the global headers.
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    label: String,
+    template: Option<Arc<RequestTemplate>>,
+}
+
+/// The templated parts of a target, rendered for every request.
+#[derive(Default)]
+struct RequestTemplate {
+    path: Option<Template>,
+    headers: Vec<(HeaderName, Template)>,
+    body: Option<Template>,
+}
+
+impl Target {
+    fn new(
+        uri: Uri,
+        path_template: Option<&str>,
+        request: &RequestSpec,
+        headers: &HeaderMap,
+        data: Option<&DataFile>,
+    ) -> Result<Self, AnyError> {
+        let mut template = RequestTemplate::default();
+
+        if let Some(path) = path_template {
+            template.path = Some(Template::compile(path, data)?);
+        }
+
+        // The target's own headers take priority over global headers of the
+        // same name.
+        let mut merged = HeaderMap::new();
+        for (k, v) in headers {
+            if !request.headers.contains_key(k) {
+                merged.append(k, v.clone());
+            }
+        }
+        for (k, v) in &request.headers {
+            merged.append(k, v.clone());
+        }
+
+        let mut static_headers = HeaderMap::new();
+        for (k, v) in &merged {
+            match v.to_str() {
+                Ok(value) if Template::is_template(value) => {
+                    template
+                        .headers
+                        .push((k.clone(), Template::compile(value, data)?));
+                }
+                _ => {
+                    static_headers.append(k, v.clone());
+                }
+            }
+        }
+
+        if let Ok(body) = std::str::from_utf8(&request.body) {
+            if Template::is_template(body) {
+                template.body = Some(Template::compile(body, data)?);
+            }
+        }
+
+        let url = match path_template {
+            Some(_) => request.url.clone(),
+            None => uri.to_string(),
+        };
+
+        let label = if request.method == Method::GET {
+            url
+        } else {
+            format!("{} {}", request.method, url)
+        };
+
+        let is_templated =
+            template.path.is_some() || !template.headers.is_empty() || template.body.is_some();
+
+        Ok(Self {
+            uri,
+            weight: request.weight,
+            method: request.method.clone(),
+            headers: static_headers,
+            body: request.body.clone(),
+            label,
+            template: is_templated.then(|| Arc::new(template)),
+        })
+    }
+
+    /// Creates a copy of the target with the parts of a request generated
+    /// by a script replaced.
+    pub fn scripted(&self, request: ScriptedRequest) -> Result<Self, AnyError> {
+        let mut target = self.clone();
+        target.template = None;
+
+        if let Some(method) = request.method {
+            target.method = Method::from_str(&method)?;
+        }
+
+        if let Some(path) = request.path {
+            let scheme = self.uri.scheme_str().unwrap_or("http");
+            let authority = self.uri.authority().map_or("", |a| a.as_str());
+            target.uri = Uri::from_str(&format!("{}://{}{}", scheme, authority, path))?;
+        }
+
+        for (k, v) in request.headers {
+            target
+                .headers
+                .insert(HeaderName::from_str(&k)?, HeaderValue::from_str(&v)?);
+        }
+
+        if let Some(body) = request.body {
+            target.body = Bytes::from(body);
+        }
+
+        Ok(target)
+    }
+
+    /// The name of the target used when breaking down the results.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Renders the path and query of the request if the url is templated.
+    pub fn render_path(&self, ctx: &RenderContext) -> Option<String> {
+        let template = self.template.as_ref()?.path.as_ref()?;
+        Some(template.render(ctx))
+    }
+
+    /// Adds the target's headers to the request, rendering any templated
+    /// header values.
+    pub fn apply_headers(
+        &self,
+        mut req: request::Builder,
+        ctx: &RenderContext,
+    ) -> request::Builder {
+        for (k, v) in &self.headers {
+            req = req.header(k, v);
+        }
+
+        if let Some(template) = self.template.as_ref() {
+            for (k, v) in template.headers.iter() {
+                req = req.header(k, v.render(ctx));
+            }
+        }
+
+        req
+    }
+
+    /// Renders the body of the request.
+    pub fn render_body(&self, ctx: &RenderContext) -> Body {
+        match self.template.as_ref().and_then(|t| t.body.as_ref()) {
+            Some(template) => Body::from(template.render(ctx)),
+            None => Body::from(self.body.clone()),
+        }
+    }
+}
+
+/// A set of targets which share the same scheme and authority, and
+/// therefore can share the same connections.
+pub struct ParsedUri {
+    pub targets: Vec<Target>,
+    pub host: String,
     pub scheme: Scheme,
-    pub addr: SocketAddr,
+    /// The addresses connections are made to, picked between by the
+    /// address strategy.
+    addrs: RwLock<Vec<Address>>,
+    pub port: u16,
+    /// The host and port looked up, the proxy's when there is one.
+    lookup_host: String,
+    lookup_port: u16,
+    strategy: AddrStrategy,
+    refresh: Option<Duration>,
+    refreshed_at: Mutex<Instant>,
+    refreshing: AtomicBool,
+    /// How long the lookup made when parsing took, until it's recorded
+    /// alongside the refreshes.
+    initial_lookup: Mutex<Option<Duration>>,
+    order: ReplayOrder,
+    dist: WeightedIndex<u32>,
+    cursor: AtomicUsize,
+    addr_cursor: AtomicUsize,
 }
 
 impl ParsedUri {
-    pub async fn parse_and_lookup(s: &str) -> Result<Self, AnyError> {
-        let uri = Uri::from_str(s)?;
+    /// Parses each of the given requests, grouping them by the authority
+    /// of their url and resolving the address of each group.
+    ///
+    /// The global headers are sent with every request and templates are
+    /// compiled with the columns of the data file, if any.
+    pub async fn parse_and_lookup(
+        requests: &[RequestSpec],
+        headers: &HeaderMap,
+        order: ReplayOrder,
+        data: Option<&DataFile>,
+        lookup: &LookupOptions,
+    ) -> Result<Vec<Self>, AnyError> {
+        let mut groups: Vec<(Scheme, String, Vec<Target>)> = Vec::new();
+
+        for request in requests {
+            let (url, path_template) = split_path_template(&request.url)?;
 
-        let scheme = Scheme::from(uri.scheme_str());
+            let uri = Uri::from_str(&url)?;
+            let scheme = Scheme::from(uri.scheme_str());
+            let authority = uri.authority().ok_or("cant find host")?.to_string();
 
-        let host = uri.host().ok_or("cant find host")?;
+            let target = Target::new(uri, path_template.as_deref(), request, headers, data)?;
+
+            match groups
+                .iter_mut()
+                .find(|(s, a, _)| *s == scheme && *a == authority)
+            {
+                Some((_, _, targets)) => targets.push(target),
+                None => groups.push((scheme, authority, vec![target])),
+            }
+        }
+
+        let mut parsed = Vec::with_capacity(groups.len());
+        for (scheme, _, targets) in groups {
+            parsed.push(Self::lookup(scheme, targets, order, lookup).await?);
+        }
+
+        Ok(parsed)
+    }
+
+    async fn lookup(
+        scheme: Scheme,
+        targets: Vec<Target>,
+        order: ReplayOrder,
+        lookup: &LookupOptions,
+    ) -> Result<Self, AnyError> {
+        let uri = &targets[0].uri;
+
+        let host = uri.host().ok_or("cant find host")?.to_owned();
 
         let port = match uri.port_u16() {
             Some(port) => port,
             None => scheme.default_port(),
         };
 
-        let addr = get_preferred_ip(host, port).await?;
+        let (lookup_host, lookup_port) = match lookup.proxy.as_ref() {
+            Some(proxy) => (proxy.host.clone(), proxy.port),
+            None => (host.clone(), port),
+        };
+
+        let resolve = lookup
+            .resolve
+            .iter()
+            .find(|r| r.matches(&lookup_host, lookup_port));
+
+        let start = Instant::now();
+        let addrs = match (lookup.unix_socket.as_ref(), resolve) {
+            (Some(path), _) => vec![Address::Unix(path.clone())],
+            (None, Some(resolve)) => {
+                let addrs = resolve
+                    .addrs
+                    .iter()
+                    .map(|ip| SocketAddr::new(*ip, lookup_port));
+                select_addrs(addrs.collect(), lookup.strategy)
+                    .into_iter()
+                    .map(Address::Tcp)
+                    .collect()
+            }
+            (None, None) => lookup_addrs(&lookup_host, lookup_port, lookup.strategy)
+                .await?
+                .into_iter()
+                .map(Address::Tcp)
+                .collect(),
+        };
+
+        // Unix sockets and overridden hosts have nothing to look up again.
+        let refresh = lookup
+            .refresh
+            .filter(|_| lookup.unix_socket.is_none() && resolve.is_none());
+        let initial_lookup = refresh.map(|_| start.elapsed());
+
+        let dist = WeightedIndex::new(targets.iter().map(|t| t.weight))
+            .map_err(|e| format!("invalid url weights for {}: {}", host, e))?;
+
+        Ok(ParsedUri {
+            targets,
+            host,
+            scheme,
+            addrs: RwLock::new(addrs),
+            port,
+            lookup_host,
+            lookup_port,
+            strategy: lookup.strategy,
+            refresh,
+            refreshed_at: Mutex::new(Instant::now()),
+            refreshing: AtomicBool::new(false),
+            initial_lookup: Mutex::new(initial_lookup),
+            order,
+            dist,
+            cursor: AtomicUsize::new(0),
+            addr_cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// The sum of all the target weights in this group.
+    pub fn total_weight(&self) -> u64 {
+        self.targets.iter().map(|t| t.weight as u64).sum()
+    }
+
+    /// Picks the address of the next connection according to the address
+    /// strategy.
+    pub fn pick_addr(&self) -> Address {
+        let addrs = self.addrs.read().unwrap();
+
+        let idx = match self.strategy {
+            AddrStrategy::RoundRobin => {
+                self.addr_cursor.fetch_add(1, Ordering::Relaxed) % addrs.len()
+            }
+            AddrStrategy::Random => rand::thread_rng().gen_range(0..addrs.len()),
+            AddrStrategy::First | AddrStrategy::Ipv6Preferred => 0,
+        };
+
+        addrs[idx].clone()
+    }
+
+    /// If the address is still one connections are made to, which a
+    /// refresh can change.
+    pub fn has_addr(&self, addr: &Address) -> bool {
+        self.addrs.read().unwrap().contains(addr)
+    }
+
+    /// Checks every address looked up can be connected to from one of the
+    /// local addresses given, so a mismatch fails before the benchmark
+    /// rather than on every connect attempt.
+    pub fn check_bind(&self, bind: &[IpAddr]) -> Result<(), AnyError> {
+        if bind.is_empty() {
+            return Ok(());
+        }
+
+        for addr in self.addrs.read().unwrap().iter() {
+            if let Address::Tcp(addr) = addr {
+                if !bind.iter().any(|local| local.is_ipv4() == addr.is_ipv4()) {
+                    return Err(format!("no bind address of the same family as {}", addr).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How often the host is looked up again, if at all.
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh
+    }
+
+    /// Takes how long the lookup made when parsing took, so it's only
+    /// recorded once. Only kept when the addresses are refreshed.
+    pub fn take_initial_lookup(&self) -> Option<Duration> {
+        self.initial_lookup.lock().unwrap().take()
+    }
+
+    /// Looks the host up again if the refresh interval has passed since
+    /// the last lookup, returning how long the lookup took or `None` when
+    /// no lookup was due.
+    ///
+    /// Only one connection refreshes at a time, the others keep using the
+    /// current addresses. A failed lookup also keeps the current
+    /// addresses until the next refresh.
+    pub async fn refresh_addrs(&self) -> Option<Result<Duration, AnyError>> {
+        let refresh = self.refresh?;
+
+        if self.refreshed_at.lock().unwrap().elapsed() < refresh {
+            return None;
+        }
+
+        if self.refreshing.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        // Another connection may have finished a refresh between the check
+        // and the swap.
+        if self.refreshed_at.lock().unwrap().elapsed() < refresh {
+            self.refreshing.store(false, Ordering::Release);
+            return None;
+        }
+
+        let start = Instant::now();
+        let result = lookup_addrs(&self.lookup_host, self.lookup_port, self.strategy).await;
+        let took = start.elapsed();
+
+        *self.refreshed_at.lock().unwrap() = Instant::now();
+        self.refreshing.store(false, Ordering::Release);
+
+        match result {
+            Ok(addrs) => {
+                *self.addrs.write().unwrap() = addrs.into_iter().map(Address::Tcp).collect();
+                Some(Ok(took))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Picks the index of the next target to send according to the
+    /// replay order.
+    pub fn pick(&self) -> usize {
+        if self.targets.len() == 1 {
+            return 0;
+        }
 
-        Ok(ParsedUri { uri, scheme, addr })
+        match self.order {
+            ReplayOrder::Random => self.dist.sample(&mut rand::thread_rng()),
+            ReplayOrder::RoundRobin => {
+                self.cursor.fetch_add(1, Ordering::Relaxed) % self.targets.len()
+            }
+        }
     }
 }
 
-async fn get_preferred_ip(host: &str, port: u16) -> Result<SocketAddr, AnyError> {
-    let addrs = tokio::net::lookup_host((host, port)).await?;
+/// Splits a templated url into its static scheme and authority and the
+/// templated path and query, e.g. `http://a/{{seq}}` -> (`http://a/`,
+/// `/{{seq}}`) or `http://a?id={{seq}}` -> (`http://a/`, `/?id={{seq}}`).
+///
+/// Templates in the scheme or authority are rejected, as connections are
+/// made before any request is rendered.
+pub(crate) fn split_path_template(url: &str) -> Result<(String, Option<String>), AnyError> {
+    if !Template::is_template(url) {
+        return Ok((url.to_string(), None));
+    }
 
-    let mut res = Err("host lookup failed".into());
+    let authority_start = url.find("://").map_or(0, |i| i + 3);
+    let path_start = url[authority_start..]
+        .find(['/', '?'])
+        .map(|i| i + authority_start)
+        .filter(|i| *i <= url.find("{{").unwrap_or_default())
+        .ok_or_else(|| {
+            format!(
+                "templates are only supported in the path and query of a url: {}",
+                url
+            )
+        })?;
 
-    for addr in addrs {
-        if addr.is_ipv4() {
-            return Ok(addr);
+    let (authority, path) = url.split_at(path_start);
+    let path = match path.starts_with('?') {
+        true => format!("/{}", path),
+        false => path.to_string(),
+    };
+
+    Ok((format!("{}/", authority), Some(path)))
+}
+
+/// Looks up the addresses of the host, only keeping the preferred address
+/// when the strategy doesn't spread connections across them.
+async fn lookup_addrs(
+    host: &str,
+    port: u16,
+    strategy: AddrStrategy,
+) -> Result<Vec<SocketAddr>, AnyError> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err("host lookup failed".into());
+    }
+
+    Ok(select_addrs(addrs, strategy))
+}
+
+/// Narrows the addresses down to the ones connections are made to, all of
+/// them when connections are spread across addresses.
+fn select_addrs(addrs: Vec<SocketAddr>, strategy: AddrStrategy) -> Vec<SocketAddr> {
+    let preferred = match strategy {
+        AddrStrategy::RoundRobin | AddrStrategy::Random => return addrs,
+        AddrStrategy::First => addrs.iter().find(|addr| addr.is_ipv4()),
+        AddrStrategy::Ipv6Preferred => addrs.iter().find(|addr| addr.is_ipv6()),
+    };
+
+    vec![*preferred.unwrap_or(&addrs[0])]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_plain_urls_untouched() {
+        let (url, path) = split_path_template("http://a:80/b?c=d").unwrap();
+        assert_eq!(url, "http://a:80/b?c=d");
+        assert_eq!(path, None);
+    }
+
+    #[test]
+    fn splits_templated_paths_and_queries() {
+        let cases = [
+            ("http://a/{{seq}}", "http://a/", "/{{seq}}"),
+            ("http://a/b/{{seq}}?c=1", "http://a/", "/b/{{seq}}?c=1"),
+            ("http://a/b?id={{uuid}}", "http://a/", "/b?id={{uuid}}"),
+            ("http://a?id={{seq}}", "http://a/", "/?id={{seq}}"),
+            ("/users/{{seq}}", "/", "/users/{{seq}}"),
+        ];
+
+        for (input, url, path) in cases {
+            let (split_url, split_path) = split_path_template(input).unwrap();
+            assert_eq!(split_url, url, "{}", input);
+            assert_eq!(split_path.as_deref(), Some(path), "{}", input);
         }
+    }
 
-        if res.is_err() {
-            res = Ok(addr);
+    #[test]
+    fn rejects_templates_before_the_path() {
+        for url in ["http://{{seq}}/a", "http://a:{{seq}}/b", "http://a{{seq}}"] {
+            let err = split_path_template(url).unwrap_err().to_string();
+            assert!(err.contains("path and query"), "{}", err);
         }
     }
 
-    res
+    #[test]
+    fn parses_resolve_overrides() {
+        let resolve: Resolve = "example.com:443:10.0.0.1,[::1]".parse().unwrap();
+        assert_eq!(resolve.host, "example.com");
+        assert_eq!(resolve.port, 443);
+        assert_eq!(
+            resolve.addrs,
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert!(resolve.matches("EXAMPLE.com", 443));
+        assert!(!resolve.matches("example.com", 80));
+
+        let resolve: Resolve = "[::1]:80:127.0.0.1".parse().unwrap();
+        assert_eq!(resolve.host, "::1");
+        assert!(resolve.matches("[::1]", 80));
+    }
+
+    #[test]
+    fn rejects_invalid_resolve_overrides() {
+        for input in ["example.com", "example.com:443", ":443:10.0.0.1"] {
+            assert!(input.parse::<Resolve>().is_err(), "{}", input);
+        }
+
+        let err = "a:http:10.0.0.1".parse::<Resolve>().unwrap_err();
+        assert!(err.to_string().starts_with("invalid port 'http'"));
+
+        let err = "a:80:10.0.0.1,nope".parse::<Resolve>().unwrap_err();
+        assert!(err.to_string().starts_with("invalid address 'nope'"));
+    }
+
+    #[test]
+    fn selects_addresses_by_strategy() {
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let v4: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let addrs = vec![v6, v4];
+
+        assert_eq!(select_addrs(addrs.clone(), AddrStrategy::First), [v4]);
+        assert_eq!(
+            select_addrs(addrs.clone(), AddrStrategy::Ipv6Preferred),
+            [v6]
+        );
+        assert_eq!(select_addrs(addrs.clone(), AddrStrategy::RoundRobin), addrs);
+        assert_eq!(select_addrs(vec![v6], AddrStrategy::First), [v6]);
+    }
+
+    async fn lookup_localhost(lookup: &LookupOptions) -> ParsedUri {
+        let requests = [RequestSpec::get("http://localhost:8080/".to_string(), 1)];
+        let mut parsed = ParsedUri::parse_and_lookup(
+            &requests,
+            &HeaderMap::new(),
+            ReplayOrder::RoundRobin,
+            None,
+            lookup,
+        )
+        .await
+        .unwrap();
+
+        parsed.remove(0)
+    }
+
+    #[tokio::test]
+    async fn refreshes_addresses_when_due() {
+        let lookup = LookupOptions {
+            refresh: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let parsed = lookup_localhost(&lookup).await;
+
+        assert!(parsed.take_initial_lookup().is_some());
+        assert!(parsed.take_initial_lookup().is_none());
+
+        assert!(matches!(parsed.refresh_addrs().await, Some(Ok(_))));
+        assert!(parsed.has_addr(&parsed.pick_addr()));
+        assert!(!parsed.has_addr(&Address::Unix("/tmp/nope.sock".into())));
+    }
+
+    #[tokio::test]
+    async fn skips_refreshes_unless_enabled() {
+        let parsed = lookup_localhost(&LookupOptions::default()).await;
+        assert!(parsed.take_initial_lookup().is_none());
+        assert!(parsed.refresh_addrs().await.is_none());
+
+        let lookup = LookupOptions {
+            refresh: Some(Duration::from_secs(60)),
+            resolve: vec!["localhost:8080:10.0.0.1".parse().unwrap()],
+            ..Default::default()
+        };
+        let parsed = lookup_localhost(&lookup).await;
+        assert!(parsed.take_initial_lookup().is_none());
+        assert!(parsed.refresh_addrs().await.is_none());
+        assert!(parsed.has_addr(&Address::Tcp("10.0.0.1:8080".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn checks_bind_address_families() {
+        let v4: IpAddr = "10.0.0.2".parse().unwrap();
+        let v6: IpAddr = "::2".parse().unwrap();
+
+        let lookup = LookupOptions {
+            strategy: AddrStrategy::RoundRobin,
+            resolve: vec!["localhost:8080:10.0.0.1,[::1]".parse().unwrap()],
+            ..Default::default()
+        };
+        let parsed = lookup_localhost(&lookup).await;
+
+        assert!(parsed.check_bind(&[]).is_ok());
+        assert!(parsed.check_bind(&[v4, v6]).is_ok());
+        assert_eq!(
+            parsed.check_bind(&[v4]).unwrap_err().to_string(),
+            "no bind address of the same family as [::1]:8080"
+        );
+
+        let lookup = LookupOptions {
+            strategy: AddrStrategy::First,
+            ..lookup
+        };
+        let parsed = lookup_localhost(&lookup).await;
+        assert!(parsed.check_bind(&[v4]).is_ok());
+        assert!(parsed.check_bind(&[v6]).is_err());
+    }
 }
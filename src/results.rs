@@ -2,6 +2,7 @@
 
 use colored::Colorize;
 use serde_json::json;
+use std::collections::BTreeMap;
 use tokio::time::Duration;
 
 use crate::utils::format_data;
@@ -22,7 +23,99 @@ fn get_percentile(request_times: &[Duration], pct: f64) -> Duration {
     Duration::from_secs_f64(avg)
 }
 
-/// Contains and handles results from the workers
+/// Works out the min, max and average of the given times, all zero when
+/// there are none.
+fn summarize(times: &[Duration]) -> (Duration, Duration, Duration) {
+    let min = times.iter().min().copied().unwrap_or_default();
+    let max = times.iter().max().copied().unwrap_or_default();
+    let avg = times.iter().sum::<Duration>() / times.len().max(1) as u32;
+
+    (min, max, avg)
+}
+
+/// Converts a time to the fractional milliseconds it's reported in.
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000_f64
+}
+
+/// Merges the entries of one breakdown into another.
+fn combine_breakdown(
+    into: &mut BTreeMap<String, TargetResult>,
+    other: BTreeMap<String, TargetResult>,
+) {
+    for (name, result) in other {
+        let combined = match into.remove(&name) {
+            Some(existing) => existing.combine(result),
+            None => result,
+        };
+        into.insert(name, combined);
+    }
+}
+
+/// Contains the results for a single target url.
+#[derive(Clone, Default)]
+pub struct TargetResult {
+    pub success: usize,
+    pub error: usize,
+
+    /// The sum of the latencies of all successful requests.
+    pub latency_total: Duration,
+    pub latency_min: Option<Duration>,
+    pub latency_max: Duration,
+}
+
+impl TargetResult {
+    /// Records a successful request which took the given time.
+    pub fn record(&mut self, took: Duration) {
+        self.success += 1;
+        self.latency_total += took;
+        self.latency_max = self.latency_max.max(took);
+        self.latency_min = Some(self.latency_min.map_or(took, |min| min.min(took)));
+    }
+
+    /// Consumes both self and other producing a combined result.
+    pub fn combine(mut self, other: Self) -> Self {
+        self.success += other.success;
+        self.error += other.error;
+        self.latency_total += other.latency_total;
+        self.latency_max = self.latency_max.max(other.latency_max);
+        self.latency_min = match (self.latency_min, other.latency_min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        self
+    }
+
+    /// Calculates the average latency of the successful requests.
+    pub fn avg_latency(&self) -> Duration {
+        if self.success == 0 {
+            return Duration::default();
+        }
+
+        self.latency_total / self.success as u32
+    }
+}
+
+/// The lifecycle of a single connection over the benchmark, a connection
+/// keeps its id when the server closes it and it reconnects.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub id: usize,
+
+    /// The number of successful requests.
+    pub requests: usize,
+    pub errors: usize,
+    pub reconnects: usize,
+
+    /// The time spent connecting, including failed attempts.
+    pub connect_time: Duration,
+}
+
+/// Contains and handles results from the workers.
+///
+/// `WorkerResult::default()` creates a empty result, useful for merging
+/// results into one consumer.
 #[derive(Default)]
 pub struct WorkerResult {
     /// The total time taken for each worker.
@@ -34,20 +127,72 @@ pub struct WorkerResult {
     /// The amount of data read from each worker.
     pub buffer_sizes: Vec<usize>,
 
+    /// The amount of data written, like the data read this includes the
+    /// headers and any TLS framing.
+    pub bytes_written: usize,
+
+    /// The decoded size of the response bodies read.
+    pub body_read: usize,
+
+    /// The size of the request bodies written.
+    pub body_written: usize,
+
     pub success: usize,
     pub error: usize,
+
+    /// The results broken down per target url.
+    pub targets: BTreeMap<String, TargetResult>,
+
+    /// The results broken down per address connected to.
+    pub addresses: BTreeMap<String, TargetResult>,
+
+    /// The most requests in flight at once on each connection.
+    pub peak_in_flight: Vec<usize>,
+
+    /// The lifecycle of each connection.
+    pub connections: Vec<ConnectionStats>,
+
+    /// The number of connections made with each HTTP version.
+    pub protocols: BTreeMap<&'static str, usize>,
+
+    /// The time taken by each TLS handshake.
+    pub tls_handshake_times: Vec<Duration>,
+
+    /// The number of TLS handshakes resuming an earlier session.
+    pub tls_resumed: usize,
+
+    /// The number of TLS connections using each negotiated version and
+    /// cipher suite.
+    pub tls_sessions: BTreeMap<String, usize>,
+
+    /// The time taken by each DNS lookup refreshing the addresses,
+    /// including the initial lookup.
+    pub dns_lookup_times: Vec<Duration>,
+
+    /// The number of DNS lookups refreshing the addresses that failed.
+    pub dns_failures: usize,
+
+    /// The number of failed attempts to connect.
+    pub connect_failures: usize,
+
+    /// The number of connections closed for each reason while the
+    /// benchmark was running.
+    pub close_reasons: BTreeMap<&'static str, usize>,
+
+    /// The number of connections which ran out of attempts to connect.
+    pub gave_up: usize,
 }
 
 impl WorkerResult {
-    /// Creates a empty result, useful for merging results into one
-    /// consumer.
-    pub fn default() -> Self {
+    /// The result of a connection which never managed to connect.
+    pub fn never_connected(id: usize, connect_time: Duration) -> Self {
         Self {
-            total_times: vec![],
-            request_times: vec![],
-            buffer_sizes: vec![],
-            success: 0,
-            error: 0,
+            connections: vec![ConnectionStats {
+                id,
+                connect_time,
+                ..ConnectionStats::default()
+            }],
+            ..Self::default()
         }
     }
 
@@ -56,9 +201,32 @@ impl WorkerResult {
         self.request_times.extend(other.request_times);
         self.total_times.extend(other.total_times);
         self.buffer_sizes.extend(other.buffer_sizes);
+        self.bytes_written += other.bytes_written;
+        self.body_read += other.body_read;
+        self.body_written += other.body_written;
+        self.peak_in_flight.extend(other.peak_in_flight);
+        self.connections.extend(other.connections);
+        self.tls_handshake_times.extend(other.tls_handshake_times);
+        self.tls_resumed += other.tls_resumed;
+        self.dns_lookup_times.extend(other.dns_lookup_times);
+        self.dns_failures += other.dns_failures;
+        self.connect_failures += other.connect_failures;
+        self.gave_up += other.gave_up;
+        for (reason, count) in other.close_reasons {
+            *self.close_reasons.entry(reason).or_default() += count;
+        }
+        for (session, count) in other.tls_sessions {
+            *self.tls_sessions.entry(session).or_default() += count;
+        }
+        for (protocol, count) in other.protocols {
+            *self.protocols.entry(protocol).or_default() += count;
+        }
         self.success += other.success;
         self.error += other.error;
 
+        combine_breakdown(&mut self.targets, other.targets);
+        combine_breakdown(&mut self.addresses, other.addresses);
+
         self
     }
 
@@ -85,6 +253,11 @@ impl WorkerResult {
         self.total_transfer() as f64 / self.avg_total_time().as_secs_f64()
     }
 
+    /// Calculates the amount of data written per second.
+    pub fn avg_upload(&self) -> f64 {
+        self.bytes_written as f64 / self.avg_total_time().as_secs_f64()
+    }
+
     /// Calculates the requests per second average.
     pub fn avg_request_per_sec(&self) -> f64 {
         let amount = self.request_times.len() as f64;
@@ -105,6 +278,10 @@ impl WorkerResult {
     /// Basic Logic:
     /// Sum(worker totals) / length = avg duration
     pub fn avg_total_time(&self) -> Duration {
+        if self.total_times.is_empty() {
+            return Duration::ZERO;
+        }
+
         let avg: f64 = self.total_times.iter().map(|dur| dur.as_secs_f64()).sum();
 
         let len = self.total_times.len() as f64;
@@ -230,21 +407,382 @@ impl WorkerResult {
         )
     }
 
+    /// Displays the data read and written, the totals are what went over
+    /// the wire while the bodies are the decoded payloads alone.
     pub fn display_transfer(&mut self) {
-        let total = self.total_transfer() as f64;
-        let rate = self.avg_transfer();
-
-        let display_total = format_data(total as f64);
-        let display_rate = format_data(rate);
+        let downloaded = format_data(self.total_transfer() as f64);
+        let download_rate = format_data(self.avg_transfer());
+        let uploaded = format_data(self.bytes_written as f64);
+        let upload_rate = format_data(self.avg_upload());
 
         println!("  Transfer:");
         println!(
-            "    Total: {:^7} Transfer Rate: {:^7}",
-            display_total.as_str().bright_cyan(),
-            format!("{}/Sec", display_rate).as_str().bright_cyan()
+            "    Download: {:^7} Rate: {:^7} Body: {:^7}",
+            downloaded.as_str().bright_cyan(),
+            format!("{}/Sec", download_rate).as_str().bright_cyan(),
+            format_data(self.body_read as f64).as_str().bright_cyan(),
+        );
+        println!(
+            "    Upload:   {:^7} Rate: {:^7} Body: {:^7}",
+            uploaded.as_str().bright_cyan(),
+            format!("{}/Sec", upload_rate).as_str().bright_cyan(),
+            format_data(self.body_written as f64).as_str().bright_cyan(),
+        );
+    }
+
+    /// Displays how many of the requested streams were actually in flight
+    /// at once, servers may limit the concurrent streams per connection.
+    pub fn display_streams(&self, streams: usize) {
+        let connections = self.peak_in_flight.len().max(1);
+        let avg = self.peak_in_flight.iter().sum::<usize>() as f64 / connections as f64;
+        let max = self
+            .peak_in_flight
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or_default();
+
+        println!("  Streams:");
+        println!(
+            "    Per Connection: {:^7} Peak In-Flight Avg: {:^7} Max: {:^7}",
+            format!("{}", streams).as_str().bright_cyan(),
+            format!("{:.2}", avg).as_str().bright_cyan(),
+            format!("{}", max).as_str().bright_cyan()
         )
     }
 
+    /// Displays the HTTP versions the connections ended up using, useful
+    /// when the version is negotiated with the server.
+    pub fn display_protocols(&self) {
+        let protocols: Vec<String> = self
+            .protocols
+            .iter()
+            .map(|(protocol, count)| {
+                format!(
+                    "{}: {}",
+                    protocol,
+                    format!("{}", count).as_str().bright_cyan()
+                )
+            })
+            .collect();
+
+        println!("  Protocols:");
+        println!("    {}", protocols.join("  "));
+    }
+
+    /// Works out the TLS handshake latency at the given percentile, 0.5
+    /// being the median.
+    fn tls_handshake_percentile(&self, pct: f64) -> Duration {
+        let mut times = self.tls_handshake_times.clone();
+        times.sort();
+
+        let idx = ((times.len() as f64 * pct).ceil() as usize).saturating_sub(1);
+        times.get(idx).copied().unwrap_or_default()
+    }
+
+    /// Displays the number of full and resumed TLS handshakes along with
+    /// how long they took.
+    pub fn display_tls_handshakes(&self) {
+        if self.tls_handshake_times.is_empty() {
+            return;
+        }
+
+        let full = self.tls_handshake_times.len() - self.tls_resumed;
+        let (min, max, avg) = summarize(&self.tls_handshake_times);
+
+        println!("  TLS Handshakes:");
+        println!(
+            "    Full: {:^7} Resumed: {:^7}",
+            format!("{}", full).as_str().bright_cyan(),
+            format!("{}", self.tls_resumed).as_str().bright_cyan(),
+        );
+        println!(
+            "    {:<7}  {:<7}  {:<7}  {:<7}  {:<7}  ",
+            "Avg".yellow(),
+            "Min".green(),
+            "P50".cyan(),
+            "P99".magenta(),
+            "Max".bright_red(),
+        );
+        println!(
+            "    {:<7}  {:<7}  {:<7}  {:<7}  {:<7}  ",
+            format!("{:.2}ms", millis(avg)),
+            format!("{:.2}ms", millis(min)),
+            format!("{:.2}ms", millis(self.tls_handshake_percentile(0.5))),
+            format!("{:.2}ms", millis(self.tls_handshake_percentile(0.99))),
+            format!("{:.2}ms", millis(max)),
+        );
+
+        for (session, count) in self.tls_sessions.iter() {
+            println!(
+                "    Negotiated: {} Connections: {}",
+                session.cyan(),
+                format!("{}", count).as_str().bright_cyan(),
+            );
+        }
+    }
+
+    /// Builds the json representation of the TLS handshakes, `null` when
+    /// no TLS connections were made.
+    fn tls_handshakes_json(&self) -> serde_json::Value {
+        if self.tls_handshake_times.is_empty() {
+            return serde_json::Value::Null;
+        }
+
+        let (min, max, avg) = summarize(&self.tls_handshake_times);
+
+        json!({
+            "full": self.tls_handshake_times.len() - self.tls_resumed,
+            "resumed": self.tls_resumed,
+            "latency_avg": millis(avg),
+            "latency_min": millis(min),
+            "latency_p50": millis(self.tls_handshake_percentile(0.5)),
+            "latency_p99": millis(self.tls_handshake_percentile(0.99)),
+            "latency_max": millis(max),
+            "sessions": self.tls_sessions,
+        })
+    }
+
+    /// Works out the min, max, average and standard deviation of the
+    /// requests sent over each connection.
+    fn requests_per_connection(&self) -> (usize, usize, f64, f64) {
+        let requests = self.connections.iter().map(|c| c.requests);
+        let min = requests.clone().min().unwrap_or_default();
+        let max = requests.clone().max().unwrap_or_default();
+
+        let len = self.connections.len().max(1) as f64;
+        let mean = requests.clone().sum::<usize>() as f64 / len;
+        let variance = requests.map(|r| (r as f64 - mean).powi(2)).sum::<f64>() / len;
+
+        (min, max, mean, variance.sqrt())
+    }
+
+    /// Displays how evenly the requests were spread over the connections,
+    /// along with how often they errored and reconnected.
+    pub fn display_per_connection(&self) {
+        if self.connections.is_empty() {
+            return;
+        }
+
+        let (min, max, mean, stdev) = self.requests_per_connection();
+
+        let errors: usize = self.connections.iter().map(|c| c.errors).sum();
+        let reconnects: usize = self.connections.iter().map(|c| c.reconnects).sum();
+        let connect_times: Vec<Duration> =
+            self.connections.iter().map(|c| c.connect_time).collect();
+        let (_, connect_max, connect_avg) = summarize(&connect_times);
+
+        println!("  Per Connection:");
+        println!(
+            "    Connections: {:^7} Requests Min: {:^7} Max: {:^7} Avg: {:^7} Stdev: {:^7}",
+            format!("{}", self.connections.len()).as_str().bright_cyan(),
+            format!("{}", min).as_str().green(),
+            format!("{}", max).as_str().bright_red(),
+            format!("{:.2}", mean).as_str().yellow(),
+            format!("{:.2}", stdev).as_str().magenta(),
+        );
+        println!(
+            "    Errors: {:^7} Reconnects: {:^7} Connect Time Avg: {:^7} Max: {:^7}",
+            format!("{}", errors).as_str().bright_red(),
+            format!("{}", reconnects).as_str().bright_cyan(),
+            format!("{:.2}ms", millis(connect_avg)),
+            format!("{:.2}ms", millis(connect_max)),
+        );
+
+        let mut connections: Vec<&ConnectionStats> = self.connections.iter().collect();
+        connections.sort_by_key(|c| c.id);
+
+        println!(
+            "    {:<7}  {:<9}  {:<7}  {:<10}  {:<10}",
+            "Id".cyan(),
+            "Requests".green(),
+            "Errors".bright_red(),
+            "Reconnects".yellow(),
+            "Connecting".magenta(),
+        );
+        for c in connections {
+            println!(
+                "    {:<7}  {:<9}  {:<7}  {:<10}  {:<10}",
+                c.id,
+                c.requests,
+                c.errors,
+                c.reconnects,
+                format!("{:.2}ms", millis(c.connect_time)),
+            );
+        }
+    }
+
+    /// Builds the json representation of the per connection report.
+    pub fn per_connection_json(&self) -> serde_json::Value {
+        let (min, max, mean, stdev) = self.requests_per_connection();
+
+        let mut connections: Vec<&ConnectionStats> = self.connections.iter().collect();
+        connections.sort_by_key(|c| c.id);
+
+        let connections: Vec<serde_json::Value> = connections
+            .into_iter()
+            .map(|c| {
+                json!({
+                    "id": c.id,
+                    "requests": c.requests,
+                    "errors": c.errors,
+                    "reconnects": c.reconnects,
+                    "connect_time": millis(c.connect_time),
+                })
+            })
+            .collect();
+
+        json!({
+            "requests_min": min,
+            "requests_max": max,
+            "requests_avg": mean,
+            "requests_std_deviation": stdev,
+            "connections": connections,
+        })
+    }
+
+    fn total_reconnects(&self) -> usize {
+        self.connections.iter().map(|c| c.reconnects).sum()
+    }
+
+    /// Displays how often connections were made again and why the previous
+    /// connections closed, skipped when every connection lasted the whole
+    /// benchmark.
+    pub fn display_reconnects(&self) {
+        let reconnects = self.total_reconnects();
+        if reconnects == 0 && self.connect_failures == 0 && self.close_reasons.is_empty() {
+            return;
+        }
+
+        println!("  Reconnects:");
+        println!(
+            "    Reconnects: {:^7} Failed Attempts: {:^7} Gave Up: {:^7}",
+            format!("{}", reconnects).as_str().bright_cyan(),
+            format!("{}", self.connect_failures).as_str().bright_red(),
+            format!("{}", self.gave_up).as_str().bright_red(),
+        );
+
+        if !self.close_reasons.is_empty() {
+            let reasons: Vec<String> = self
+                .close_reasons
+                .iter()
+                .map(|(reason, count)| {
+                    format!("{}: {}", reason, format!("{}", count).as_str().yellow())
+                })
+                .collect();
+
+            println!("    Closed: {}", reasons.join("  "));
+        }
+    }
+
+    fn reconnects_json(&self) -> serde_json::Value {
+        json!({
+            "reconnects": self.total_reconnects(),
+            "failed_attempts": self.connect_failures,
+            "gave_up": self.gave_up,
+            "close_reasons": self.close_reasons,
+        })
+    }
+
+    /// Displays the number of DNS lookups made refreshing the addresses,
+    /// including the initial lookup, along with how long they took.
+    pub fn display_dns_lookups(&self) {
+        if self.dns_lookup_times.is_empty() && self.dns_failures == 0 {
+            return;
+        }
+
+        let (_, max, avg) = summarize(&self.dns_lookup_times);
+
+        println!("  DNS Lookups:");
+        println!(
+            "    Lookups: {:^7} Failures: {:^7} Avg: {:^7} Max: {:^7}",
+            format!("{}", self.dns_lookup_times.len())
+                .as_str()
+                .bright_cyan(),
+            format!("{}", self.dns_failures).as_str().bright_red(),
+            format!("{:.2}ms", millis(avg)),
+            format!("{:.2}ms", millis(max)),
+        );
+    }
+
+    /// Builds the json representation of the DNS lookups, `null` when the
+    /// addresses were never refreshed.
+    fn dns_lookups_json(&self) -> serde_json::Value {
+        if self.dns_lookup_times.is_empty() && self.dns_failures == 0 {
+            return serde_json::Value::Null;
+        }
+
+        let (_, max, avg) = summarize(&self.dns_lookup_times);
+
+        json!({
+            "lookups": self.dns_lookup_times.len(),
+            "failures": self.dns_failures,
+            "latency_avg": millis(avg),
+            "latency_max": millis(max),
+        })
+    }
+
+    /// Displays the per url breakdown, only useful when benchmarking more
+    /// than one url.
+    pub fn display_targets(&mut self) {
+        self.display_breakdown("Targets", &self.targets);
+    }
+
+    /// Displays the per address breakdown, only useful when connections
+    /// are spread across more than one address.
+    pub fn display_addresses(&self) {
+        self.display_breakdown("Addresses", &self.addresses);
+    }
+
+    fn display_breakdown(&self, title: &str, breakdown: &BTreeMap<String, TargetResult>) {
+        if breakdown.len() < 2 {
+            return;
+        }
+
+        let modified = 1000_f64;
+        let avg_time = self.avg_total_time().as_secs_f64();
+
+        println!("  {}:", title);
+        for (url, target) in breakdown.iter() {
+            println!(
+                "    {} Total: {} Req/Sec: {} Avg: {} Errors: {}",
+                url.cyan(),
+                format!("{}", target.success).as_str().bright_cyan(),
+                format!("{:.2}", target.success as f64 / avg_time)
+                    .as_str()
+                    .bright_cyan(),
+                format!("{:.2}ms", target.avg_latency().as_secs_f64() * modified).yellow(),
+                format!("{}", target.error).as_str().bright_red(),
+            );
+        }
+    }
+
+    /// Builds the json representation of a breakdown, naming each entry
+    /// with the given key.
+    fn breakdown_json(
+        &self,
+        key: &str,
+        breakdown: &BTreeMap<String, TargetResult>,
+    ) -> serde_json::Value {
+        let modified = 1000_f64;
+        let avg_time = self.avg_total_time().as_secs_f64();
+
+        breakdown
+            .iter()
+            .map(|(name, target)| {
+                json!({
+                    key: name,
+                    "requests_total": target.success,
+                    "requests_avg": target.success as f64 / avg_time,
+                    "errors_total": target.error,
+                    "latency_avg": target.avg_latency().as_secs_f64() * modified,
+                    "latency_max": target.latency_max.as_secs_f64() * modified,
+                    "latency_min": target.latency_min.unwrap_or_default().as_secs_f64() * modified,
+                })
+            })
+            .collect()
+    }
+
     pub fn display_percentile_table(&mut self) {
         self.sort_request_times();
 
@@ -294,11 +832,16 @@ impl WorkerResult {
     }
 
     pub fn display_json(&self) {
+        println!("{}", self.json())
+    }
+
+    /// Builds the json representation of the results.
+    pub fn json(&self) -> serde_json::Value {
         // prevent div-by-zero panics
         if self.total_requests() == 0 {
             let null = None::<()>;
 
-            let out = json!({
+            return json!({
                 "latency_avg": null,
                 "latency_max": null,
                 "latency_min": null,
@@ -306,13 +849,25 @@ impl WorkerResult {
 
                 "transfer_total": null,
                 "transfer_rate": null,
+                "transfer_upload_total": null,
+                "transfer_upload_rate": null,
+                "body_download_total": null,
+                "body_upload_total": null,
 
                 "requests_total": 0,
                 "requests_avg": null,
-            });
 
-            println!("{}", out.to_string());
-            return;
+                "targets": [],
+                "addresses": [],
+
+                "protocols": self.protocols,
+
+                "tls_handshakes": self.tls_handshakes_json(),
+
+                "dns": self.dns_lookups_json(),
+
+                "reconnects": self.reconnects_json(),
+            });
         }
 
         let modified = 1000_f64;
@@ -327,7 +882,7 @@ impl WorkerResult {
         let total_requests = self.total_requests();
         let avg_request_per_sec = self.avg_request_per_sec();
 
-        let out = json!({
+        json!({
             "latency_avg": avg,
             "latency_max": max,
             "latency_min": min,
@@ -335,11 +890,97 @@ impl WorkerResult {
 
             "transfer_total": total,
             "transfer_rate": rate,
+            "transfer_upload_total": self.bytes_written,
+            "transfer_upload_rate": self.avg_upload(),
+            "body_download_total": self.body_read,
+            "body_upload_total": self.body_written,
 
             "requests_total": total_requests,
             "requests_avg": avg_request_per_sec,
-        });
 
-        println!("{}", out.to_string())
+            "targets": self.breakdown_json("url", &self.targets),
+            "addresses": self.breakdown_json("address", &self.addresses),
+
+            "in_flight_peak": self.peak_in_flight.iter().max(),
+
+            "protocols": self.protocols,
+
+            "tls_handshakes": self.tls_handshakes_json(),
+
+            "dns": self.dns_lookups_json(),
+
+            "reconnects": self.reconnects_json(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_nothing_as_zero() {
+        let result = WorkerResult::default();
+        assert_eq!(result.avg_total_time(), Duration::ZERO);
+
+        let result = WorkerResult::never_connected(0, Duration::from_secs(1));
+        assert_eq!(result.avg_total_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn summarizes_times() {
+        let ms = Duration::from_millis;
+        assert_eq!(summarize(&[ms(4), ms(1), ms(7)]), (ms(1), ms(7), ms(4)));
+        assert_eq!(summarize(&[]), (ms(0), ms(0), ms(0)));
+        assert_eq!(millis(Duration::from_micros(1500)), 1.5);
+    }
+
+    #[test]
+    fn averages_total_times() {
+        let result = WorkerResult {
+            total_times: vec![Duration::from_secs(1), Duration::from_secs(3)],
+            ..WorkerResult::default()
+        };
+
+        assert_eq!(result.avg_total_time(), Duration::from_secs(2));
+    }
+
+    fn connection(id: usize, requests: usize, connect_ms: u64) -> WorkerResult {
+        WorkerResult {
+            connections: vec![ConnectionStats {
+                id,
+                requests,
+                errors: 1,
+                reconnects: id,
+                connect_time: Duration::from_millis(connect_ms),
+            }],
+            ..WorkerResult::default()
+        }
+    }
+
+    #[test]
+    fn reports_per_connection_stats() {
+        let result = connection(2, 30, 4)
+            .combine(connection(0, 10, 2))
+            .combine(WorkerResult::never_connected(1, Duration::from_millis(6)));
+
+        assert_eq!(result.connections.len(), 3);
+
+        let (min, max, mean, _) = result.requests_per_connection();
+        assert_eq!((min, max), (0, 30));
+        assert!((mean - 40.0 / 3.0).abs() < 1e-9);
+
+        let json = result.per_connection_json();
+        assert_eq!(json["requests_min"], 0);
+        assert_eq!(json["requests_max"], 30);
+
+        let connections = json["connections"].as_array().unwrap();
+        let ids: Vec<_> = connections.iter().map(|c| c["id"].clone()).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(connections[0]["requests"], 10);
+        assert_eq!(connections[0]["errors"], 1);
+        assert_eq!(connections[1]["requests"], 0);
+        assert_eq!(connections[1]["connect_time"], 6.0);
+        assert_eq!(connections[2]["reconnects"], 2);
     }
 }
@@ -0,0 +1,329 @@
+use crate::error::AnyError;
+use crate::results::WorkerResult;
+
+use std::path::PathBuf;
+
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+
+/// A Rhai script hooking into the benchmark, similar to wrk's lua scripts.
+///
+/// The script may define any of the following functions:
+/// - `request()` returning a map with any of `method`, `path`, `headers`
+///   and `body` to override the request being sent, or `()` to send the
+///   request unchanged.
+/// - `response(status, headers, body)` returning `false` to count the
+///   response as an error, or `true` to count it as a success.
+/// - `done(summary)` called once the benchmark has finished with a map
+///   of the results, any string returned is displayed.
+///
+/// Each connection gets its own engine, shared by all of the connection's
+/// streams. The top level of the script is ran once per connection with
+/// `connection_id` in scope, and functions can keep per connection state
+/// in the `this` map.
+#[derive(Debug)]
+pub struct Script {
+    ast: AST,
+    has_request: bool,
+    has_response: bool,
+    has_done: bool,
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self, AnyError> {
+        let ast = Engine::new()
+            .compile_file(PathBuf::from(path))
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self::new(ast))
+    }
+
+    fn new(ast: AST) -> Self {
+        let has_fn = |name: &str| ast.iter_functions().any(|f| f.name == name);
+
+        Self {
+            has_request: has_fn("request"),
+            has_response: has_fn("response"),
+            has_done: has_fn("done"),
+            ast,
+        }
+    }
+
+    /// Creates a new script engine for a single connection.
+    pub fn instance(&self, connection_id: usize) -> Result<ScriptInstance<'_>, AnyError> {
+        let engine = Engine::new();
+
+        let mut scope = Scope::new();
+        scope.push_constant("connection_id", connection_id as i64);
+
+        engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| e.to_string())?;
+
+        Ok(ScriptInstance {
+            script: self,
+            engine,
+            scope,
+            state: Dynamic::from_map(Map::new()),
+        })
+    }
+
+    /// Calls the script's `done` function with a summary of the results.
+    pub fn done(&self, result: &WorkerResult) -> Result<Option<String>, AnyError> {
+        if !self.has_done {
+            return Ok(None);
+        }
+
+        let modified = 1000_f64;
+
+        let mut summary = Map::new();
+        summary.insert("requests".into(), (result.success as i64).into());
+        summary.insert("errors".into(), (result.error as i64).into());
+        summary.insert(
+            "duration".into(),
+            result.avg_total_time().as_secs_f64().into(),
+        );
+        summary.insert(
+            "transfer_total".into(),
+            (result.total_transfer() as i64).into(),
+        );
+
+        if result.total_requests() > 0 {
+            summary.insert(
+                "latency_avg".into(),
+                (result.avg_request_latency().as_secs_f64() * modified).into(),
+            );
+            summary.insert(
+                "latency_min".into(),
+                (result.min_request_latency().as_secs_f64() * modified).into(),
+            );
+            summary.insert(
+                "latency_max".into(),
+                (result.max_request_latency().as_secs_f64() * modified).into(),
+            );
+            summary.insert("requests_avg".into(), result.avg_request_per_sec().into());
+        }
+
+        // The top level of the script is per connection so is not ran here.
+        let options = CallFnOptions::new().eval_ast(false);
+        let out: Dynamic = Engine::new()
+            .call_fn_with_options(options, &mut Scope::new(), &self.ast, "done", (summary,))
+            .map_err(|e| e.to_string())?;
+
+        Ok(out.into_string().ok())
+    }
+}
+
+/// The parts of a request generated by a script's `request` function.
+#[derive(Default)]
+pub struct ScriptedRequest {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+/// A script engine owned by a single connection.
+pub struct ScriptInstance<'a> {
+    script: &'a Script,
+    engine: Engine,
+    scope: Scope<'static>,
+    state: Dynamic,
+}
+
+impl<'a> ScriptInstance<'a> {
+    /// Returns if the script inspects the responses.
+    pub fn has_response(&self) -> bool {
+        self.script.has_response
+    }
+
+    /// Calls the script's `request` function if it has one.
+    pub fn request(&mut self) -> Result<Option<ScriptedRequest>, AnyError> {
+        if !self.script.has_request {
+            return Ok(None);
+        }
+
+        let out = self.call("request", ())?;
+        if out.is_unit() {
+            return Ok(None);
+        }
+
+        let map = out
+            .try_cast::<Map>()
+            .ok_or("request() must return a map or ()")?;
+
+        let get = |key: &str| map.get(key).map(|v| v.to_string());
+
+        let mut request = ScriptedRequest {
+            method: get("method"),
+            path: get("path"),
+            body: get("body"),
+            ..Default::default()
+        };
+
+        if let Some(headers) = map.get("headers") {
+            let headers = headers
+                .read_lock::<Map>()
+                .ok_or("request() headers must be a map")?;
+
+            for (k, v) in headers.iter() {
+                request.headers.push((k.to_string(), v.to_string()));
+            }
+        }
+
+        Ok(Some(request))
+    }
+
+    /// Calls the script's `response` function if it has one, returning the
+    /// script's verdict on whether the response was successful.
+    pub fn response(
+        &mut self,
+        status: u16,
+        headers: &http::HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<bool>, AnyError> {
+        if !self.script.has_response {
+            return Ok(None);
+        }
+
+        let mut header_map = Map::new();
+        for (k, v) in headers {
+            let value = String::from_utf8_lossy(v.as_bytes()).to_string();
+            header_map.insert(k.as_str().into(), value.into());
+        }
+
+        let body = String::from_utf8_lossy(body).to_string();
+
+        let out = self.call("response", (status as i64, header_map, body))?;
+
+        Ok(out.as_bool().ok())
+    }
+
+    fn call(&mut self, name: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, AnyError> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+
+        let out = self
+            .engine
+            .call_fn_with_options(options, &mut self.scope, &self.script.ast, name, args)
+            .map_err(|e| e.to_string())?;
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use http::HeaderMap;
+
+    fn compile(source: &str) -> Script {
+        Script::new(Engine::new().compile(source).unwrap())
+    }
+
+    #[test]
+    fn overrides_requests() {
+        let script = compile(
+            r#"
+            let prefix = "/conn/" + connection_id;
+
+            fn request() {
+                this.count = (this.count ?? 0) + 1;
+                #{
+                    method: "POST",
+                    path: prefix + "/" + this.count,
+                    headers: #{ "x-count": this.count },
+                    body: "hello",
+                }
+            }
+            "#,
+        );
+
+        let mut instance = script.instance(7).unwrap();
+        instance.request().unwrap();
+        let request = instance.request().unwrap().unwrap();
+
+        assert_eq!(request.method.as_deref(), Some("POST"));
+        assert_eq!(request.path.as_deref(), Some("/conn/7/2"));
+        assert_eq!(request.headers, [("x-count".to_string(), "2".to_string())]);
+        assert_eq!(request.body.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn leaves_requests_unchanged() {
+        for source in ["fn request() { () }", "fn response(s, h, b) { true }"] {
+            let script = compile(source);
+            let mut instance = script.instance(0).unwrap();
+            assert!(instance.request().unwrap().is_none(), "{}", source);
+        }
+
+        let script = compile("fn request() { 1 }");
+        assert!(script.instance(0).unwrap().request().is_err());
+    }
+
+    #[test]
+    fn decides_if_responses_succeeded() {
+        let script = compile(
+            r#"
+            fn response(status, headers, body) {
+                status < 500 && headers["x-ok"] == "yes" && body.contains("fine")
+            }
+            "#,
+        );
+        let mut instance = script.instance(0).unwrap();
+        assert!(instance.has_response());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ok", "yes".parse().unwrap());
+
+        assert_eq!(
+            instance.response(404, &headers, b"fine").unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            instance.response(503, &headers, b"fine").unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            instance.response(200, &headers, b"bad").unwrap(),
+            Some(false)
+        );
+
+        let script = compile("fn request() { () }");
+        let mut instance = script.instance(0).unwrap();
+        assert!(!instance.has_response());
+        assert_eq!(instance.response(200, &headers, b"").unwrap(), None);
+    }
+
+    #[test]
+    fn summarises_results() {
+        let script = compile(
+            r#"
+            fn done(summary) {
+                `${summary.requests} ok, ${summary.errors} failed, max ${summary.latency_max}ms`
+            }
+            "#,
+        );
+
+        let result = WorkerResult {
+            total_times: vec![Duration::from_secs(1)],
+            request_times: vec![Duration::from_millis(2), Duration::from_millis(4)],
+            success: 2,
+            error: 1,
+            ..Default::default()
+        };
+
+        let out = script.done(&result).unwrap();
+        assert_eq!(out.as_deref(), Some("2 ok, 1 failed, max 4.0ms"));
+
+        // Without any requests the latencies are left out.
+        let script = compile("fn done(summary) { summary.contains(\"latency_avg\").to_string() }");
+        let out = script.done(&WorkerResult::default()).unwrap();
+        assert_eq!(out.as_deref(), Some("false"));
+
+        assert_eq!(compile("let x = 1;").done(&result).unwrap(), None);
+    }
+}